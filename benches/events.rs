@@ -0,0 +1,348 @@
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{info, span, Level, Subscriber};
+use tracing_bunyan_formatter::{BunyanFormattingLayer, FieldOrdering, JsonStorageLayer};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+/// Count allocations, to measure how many of them are needed to emit a record.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Measure the number of allocations made by a benchmark, instead of its duration.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> Self::Intermediate {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: Self::Intermediate) -> Self::Value {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+impl ValueFormatter for Allocations {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocations"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        match *throughput {
+            Throughput::Elements(elements) => {
+                for value in values {
+                    *value /= elements as f64;
+                }
+                "allocations/element"
+            }
+            Throughput::Bytes(bytes) | Throughput::BytesDecimal(bytes) => {
+                for value in values {
+                    *value /= bytes as f64;
+                }
+                "allocations/byte"
+            }
+        }
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocations"
+    }
+}
+
+fn subscriber(field_ordering: FieldOrdering) -> impl tracing::Subscriber + Send + Sync {
+    let formatting_layer = BunyanFormattingLayer::builder("bench".into(), std::io::sink)
+        .field_ordering(field_ordering)
+        .build()
+        .unwrap();
+    Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer)
+}
+
+fn event() {
+    info!(
+        user_id = 42,
+        path = "/api/v1/users",
+        latency = 1.5,
+        cached = false,
+        method = ?"GET",
+        "request handled"
+    );
+}
+
+/// Run `f` inside three nested spans.
+fn in_nested_spans(f: impl FnOnce()) {
+    let outer = span!(
+        Level::INFO,
+        "request",
+        request_id = "d1b1d1a8",
+        tenant = "acme"
+    );
+    let _outer = outer.enter();
+    let middle = span!(Level::INFO, "handler", route = "users");
+    let _middle = middle.enter();
+    let inner = span!(Level::INFO, "db", table = "users", attempt = 1);
+    let _inner = inner.enter();
+    f();
+}
+
+fn events_in_nested_spans(c: &mut Criterion) {
+    let mut group = c.benchmark_group("event in nested spans");
+    for (name, field_ordering) in [
+        ("unordered", FieldOrdering::Unordered),
+        ("declaration", FieldOrdering::Declaration),
+        ("alphabetical", FieldOrdering::Alphabetical),
+    ] {
+        tracing::subscriber::with_default(subscriber(field_ordering), || {
+            in_nested_spans(|| {
+                group.bench_function(name, |b| b.iter(event));
+            });
+        });
+    }
+    group.finish();
+}
+
+/// The allocations made by nested spans, with and without an event: emitting an event may
+/// not allocate at all, which criterion can't measure on its own.
+fn allocations_in_nested_spans(c: &mut Criterion<Allocations>) {
+    let mut group = c.benchmark_group("allocations in nested spans");
+    tracing::subscriber::with_default(subscriber(FieldOrdering::Unordered), || {
+        group.bench_function("no event", |b| b.iter(|| in_nested_spans(|| ())));
+    });
+    for (name, field_ordering) in [
+        ("unordered", FieldOrdering::Unordered),
+        ("declaration", FieldOrdering::Declaration),
+        ("alphabetical", FieldOrdering::Alphabetical),
+    ] {
+        tracing::subscriber::with_default(subscriber(field_ordering), || {
+            group.bench_function(format!("{} event", name), |b| {
+                b.iter(|| in_nested_spans(event))
+            });
+        });
+    }
+    group.finish();
+}
+
+fn spans(c: &mut Criterion) {
+    tracing::subscriber::with_default(subscriber(FieldOrdering::Unordered), || {
+        c.bench_function("nested spans lifecycle", |b| {
+            b.iter(|| in_nested_spans(|| ()))
+        });
+    });
+}
+
+/// The depth of the span trees created by deep instrumentation, e.g. nested `#[instrument]`
+/// functions.
+const DEPTH: u64 = 100;
+
+fn deep_subscriber(span_events: FmtSpan) -> impl tracing::Subscriber + Send + Sync {
+    let formatting_layer = BunyanFormattingLayer::builder("bench".into(), std::io::sink)
+        .span_events(span_events)
+        .build()
+        .unwrap();
+    Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer)
+}
+
+/// Run `f` inside `depth` nested spans, each with its own fields.
+fn in_deep_spans(depth: u64, f: &dyn Fn()) {
+    if depth == 0 {
+        return f();
+    }
+    // Spans at different levels have different fields, as nested functions do.
+    let span = match depth % 8 {
+        0 => span!(Level::INFO, "handler", depth, route = "/users/{id}"),
+        1 => span!(Level::INFO, "authenticate", depth, user_id = 42),
+        2 => span!(Level::INFO, "authorize", depth, role = "admin"),
+        3 => span!(Level::INFO, "service", depth, service = "users"),
+        4 => span!(Level::INFO, "cache", depth, cache_hit = false),
+        5 => span!(Level::INFO, "repository", depth, table = "users"),
+        6 => span!(Level::INFO, "pool", depth, connection_id = 7),
+        _ => span!(
+            Level::INFO,
+            "query",
+            depth,
+            statement = "SELECT * FROM users"
+        ),
+    };
+    let _enter = span.enter();
+    in_deep_spans(depth - 1, f);
+}
+
+/// Run `f` inside a request span, carrying the many fields inherited by the spans below it.
+fn in_request(f: &dyn Fn()) {
+    let span = span!(
+        Level::INFO,
+        "http_request",
+        http.method = "GET",
+        http.route = "/users/{id}",
+        http.target = "/users/42?expand=orders",
+        http.scheme = "https",
+        http.host = "api.example.com",
+        http.flavor = "1.1",
+        http.user_agent = "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/119.0",
+        http.client_ip = "203.0.113.7",
+        request_id = "7d0b6c0e-4a1b-4c52-9a8e-2f1d3b5e6a90",
+        trace_id = "4bf92f3577b34da6a3ce929d0e0e4736",
+        tenant = "acme",
+        region = "eu-west-1",
+        deployment = "blue",
+        version = "1.42.0",
+        feature_flags = "new-checkout,dark-mode",
+        user_id = 42,
+    );
+    let _enter = span.enter();
+    f();
+}
+
+/// The baseline for [`JsonStorageLayer`]: the fields of each span start from a copy of the
+/// fields of its parent, as `JsonStorageLayer` used to store them.
+struct CopyingStorageLayer;
+
+#[derive(Clone, Default)]
+struct CopiedFields(HashMap<&'static str, serde_json::Value>);
+
+impl Visit for CopiedFields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value).into());
+    }
+}
+
+impl<S> Layer<S> for CopyingStorageLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let mut fields = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<CopiedFields>().cloned())
+            .unwrap_or_default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+}
+
+/// Storing the fields of deep span trees, against a storage copying them into every span.
+///
+/// Formatting is left out: records still walk every field inherited by their span, so with
+/// span records the cost of formatting dominates and sharing storages doesn't make it cheaper.
+fn deep_span_tree_storage(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deep span tree storage");
+    tracing::subscriber::with_default(Registry::default().with(CopyingStorageLayer), || {
+        group.bench_function("copying", |b| {
+            b.iter(|| in_request(&|| in_deep_spans(DEPTH, &|| ())))
+        });
+    });
+    tracing::subscriber::with_default(Registry::default().with(JsonStorageLayer), || {
+        group.bench_function("shared", |b| {
+            b.iter(|| in_request(&|| in_deep_spans(DEPTH, &|| ())))
+        });
+    });
+    group.finish();
+}
+
+fn deep_span_trees(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deep span tree");
+    for (name, span_events) in [
+        ("without span records", FmtSpan::NONE),
+        ("with default span records", FmtSpan::NEW | FmtSpan::CLOSE),
+    ] {
+        tracing::subscriber::with_default(deep_subscriber(span_events), || {
+            group.bench_function(format!("{} - no event", name), |b| {
+                b.iter(|| in_request(&|| in_deep_spans(DEPTH, &|| ())))
+            });
+            group.bench_function(format!("{} - event at the leaf", name), |b| {
+                b.iter(|| in_request(&|| in_deep_spans(DEPTH, &event)))
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    events_in_nested_spans,
+    spans,
+    deep_span_tree_storage,
+    deep_span_trees
+);
+criterion_group!(
+    name = allocations;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = allocations_in_nested_spans
+);
+criterion_main!(benches, allocations);
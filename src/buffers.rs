@@ -0,0 +1,85 @@
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+/// Buffers larger than this are shrunk after use, to avoid holding on to the memory
+/// needed by an exceptionally large record.
+const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// Per-thread buffers used to format the core fields of a record, reused from one record
+/// to the next to avoid allocating on every span and event.
+#[derive(Default)]
+pub(crate) struct Buffers {
+    /// The `msg` field of the record.
+    pub(crate) message: String,
+    /// The `time` field of the record.
+    pub(crate) time: String,
+}
+
+trait Reusable: Default {
+    fn clear(&mut self);
+
+    fn shrink(&mut self);
+}
+
+impl Reusable for Buffers {
+    fn clear(&mut self) {
+        self.message.clear();
+        self.time.clear();
+    }
+
+    fn shrink(&mut self) {
+        self.message.shrink_to(MAX_RETAINED_CAPACITY);
+        self.time.shrink_to(MAX_RETAINED_CAPACITY);
+    }
+}
+
+impl Reusable for Vec<u8> {
+    fn clear(&mut self) {
+        Vec::clear(self);
+    }
+
+    fn shrink(&mut self) {
+        self.shrink_to(MAX_RETAINED_CAPACITY);
+    }
+}
+
+thread_local! {
+    static BUFFERS: RefCell<Buffers> = RefCell::new(Buffers::default());
+    static RECORD: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f` with the empty buffers of the current thread.
+///
+/// Fresh buffers are used if the ones of the current thread are already in use, e.g.
+/// when a record is emitted while writing another one, or if they have already been
+/// destroyed because the thread is exiting.
+pub(crate) fn with_buffers<R>(f: impl FnOnce(&mut Buffers) -> R) -> R {
+    with_reused(&BUFFERS, f)
+}
+
+/// Run `f` with the empty buffer of the current thread used to serialize a whole record.
+///
+/// See [`with_buffers`] for the cases in which a fresh buffer is used instead.
+pub(crate) fn with_record_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    with_reused(&RECORD, f)
+}
+
+fn with_reused<T: Reusable, R>(
+    key: &'static LocalKey<RefCell<T>>,
+    f: impl FnOnce(&mut T) -> R,
+) -> R {
+    let mut f = Some(f);
+    let reused = key.try_with(|buffers| {
+        let mut buffers = buffers.try_borrow_mut().ok()?;
+        let f = f.take()?;
+        buffers.clear();
+        let result = f(&mut buffers);
+        buffers.shrink();
+        Some(result)
+    });
+    match (reused, f) {
+        (Ok(Some(result)), _) => result,
+        (_, Some(f)) => f(&mut T::default()),
+        (_, None) => unreachable!("The buffers have been used, this is a bug"),
+    }
+}
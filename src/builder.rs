@@ -0,0 +1,495 @@
+use crate::error_handler::ErrorHandler;
+use crate::fields::{FieldOrdering, KeyCollisionPolicy};
+use crate::formatting_layer::{
+    default_span_events, BunyanFormattingLayer, LevelMapping, SourceLocation, SpanIds,
+    BUNYAN_REQUIRED_FIELDS,
+};
+use crate::level::BunyanLevel;
+use crate::redaction::{RedactionRule, Redactor};
+use crate::sink::RecordSink;
+use crate::timestamp::BunyanTime;
+use ahash::{HashSet, HashSetExt};
+use serde_json::Value;
+use std::fmt;
+use tracing::Metadata;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::time::FormatTime;
+
+/// A builder for [`BunyanFormattingLayer`], to customise every aspect of the emitted records.
+///
+/// All options are validated once, in [`BunyanFormattingLayerBuilder::build`].
+///
+/// ```rust
+/// use serde_json::json;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, SourceLocation};
+///
+/// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+///     .default_field("env", json!("production"))
+///     .skip_fields(["file", "line"])
+///     .hostname("my-host")
+///     .source_location(SourceLocation::Nested)
+///     .build()
+///     .expect("Invalid configuration for the Bunyan formatting layer");
+/// ```
+pub struct BunyanFormattingLayerBuilder<W: RecordSink> {
+    name: String,
+    make_writer: W,
+    pid: Option<u32>,
+    hostname: Option<String>,
+    bunyan_version: u8,
+    default_fields: Vec<(String, Value)>,
+    skip_fields: Vec<String>,
+    source_location: SourceLocation,
+    timer: Box<dyn FormatTime + Send + Sync>,
+    span_events: FmtSpan,
+    key_collision_policy: KeyCollisionPolicy,
+    field_ordering: FieldOrdering,
+    redaction_rules: Vec<RedactionRule>,
+    span_ids: SpanIds,
+    span_list: bool,
+    span_path: bool,
+    json_storage: bool,
+    #[cfg(feature = "opentelemetry")]
+    opentelemetry_ids: bool,
+    error_handler: ErrorHandler,
+    level_mapping: Option<LevelMapping>,
+}
+
+/// The error returned by [`BunyanFormattingLayerBuilder::build`] when the configuration is invalid.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// A required core field of the Bunyan format (e.g. `name`) was asked to be skipped.
+    SkippedCoreField(String),
+    /// A default field uses the key of a required core field of the Bunyan format (e.g. `level`).
+    ReservedDefaultField(String),
+    /// The pattern of a [`RedactionRule`] is not a valid regular expression.
+    InvalidRedactionPattern(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::SkippedCoreField(field) => write!(
+                f,
+                "{} is a core field in the bunyan log format, it can't be skipped",
+                field
+            ),
+            BuildError::ReservedDefaultField(field) => write!(
+                f,
+                "{} is a core field in the bunyan log format, it can't be used as a default field",
+                field
+            ),
+            BuildError::InvalidRedactionPattern(pattern) => write!(
+                f,
+                "{} is not a valid regular expression for a redaction rule",
+                pattern
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// This error will be returned in [`BunyanFormattingLayer::skip_fields`] if trying to skip a core field.
+#[deprecated(since = "0.3.11", note = "Use `BuildError` instead")]
+pub type SkipFieldError = BuildError;
+
+impl<W: RecordSink> BunyanFormattingLayerBuilder<W> {
+    pub(crate) fn new(name: String, make_writer: W) -> Self {
+        Self {
+            name,
+            make_writer,
+            pid: None,
+            hostname: None,
+            bunyan_version: 0,
+            default_fields: Vec::new(),
+            skip_fields: Vec::new(),
+            source_location: SourceLocation::default(),
+            timer: Box::new(BunyanTime::default()),
+            span_events: default_span_events(),
+            key_collision_policy: KeyCollisionPolicy::default(),
+            field_ordering: FieldOrdering::default(),
+            redaction_rules: Vec::new(),
+            span_ids: SpanIds::default(),
+            span_list: false,
+            span_path: false,
+            json_storage: false,
+            #[cfg(feature = "opentelemetry")]
+            opentelemetry_ids: true,
+            error_handler: ErrorHandler::default(),
+            level_mapping: None,
+        }
+    }
+
+    /// Add a field to all formatted records.
+    ///
+    /// Setting the same key twice overrides the previous value.
+    pub fn default_field(mut self, key: impl Into<String>, value: Value) -> Self {
+        let key = key.into();
+        match self.default_fields.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.default_fields.push((key, value)),
+        }
+        self
+    }
+
+    /// Add a set of fields to all formatted records.
+    pub fn default_fields(self, fields: impl IntoIterator<Item = (String, Value)>) -> Self {
+        fields.into_iter().fold(self, |builder, (key, value)| {
+            builder.default_field(key, value)
+        })
+    }
+
+    /// Fields to skip when formatting records.
+    ///
+    /// Required core Bunyan fields (e.g. `name`) can't be skipped, while optional core Bunyan
+    /// fields (e.g. `line`, `file`, `target`) can. Fields moved to a namespaced key by
+    /// [`KeyCollisionPolicy::Namespace`] are skipped by their name as well.
+    pub fn skip_fields<Field: Into<String>>(
+        mut self,
+        fields: impl IntoIterator<Item = Field>,
+    ) -> Self {
+        self.skip_fields.extend(fields.into_iter().map(Into::into));
+        self
+    }
+
+    /// Override the `hostname` attached to all records.
+    ///
+    /// It defaults to the hostname of the machine if the `hostname` feature is enabled,
+    /// to an empty string otherwise.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Override the `pid` attached to all records.
+    ///
+    /// It defaults to the id of the current process.
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Override the version of the Bunyan format (`v`) attached to all records.
+    ///
+    /// It defaults to `0`, the only version of the format published so far.
+    pub fn bunyan_version(mut self, bunyan_version: u8) -> Self {
+        self.bunyan_version = bunyan_version;
+        self
+    }
+
+    /// Choose how the call site of each record should be emitted.
+    ///
+    /// See [`BunyanFormattingLayer::source_location`] for more details.
+    pub fn source_location(mut self, source_location: SourceLocation) -> Self {
+        self.source_location = source_location;
+        self
+    }
+
+    /// Set the timer used to populate the `time` field of each record.
+    ///
+    /// See [`BunyanFormattingLayer::timer`] for more details.
+    pub fn timer(mut self, timer: impl FormatTime + Send + Sync + 'static) -> Self {
+        self.timer = Box::new(timer);
+        self
+    }
+
+    /// Choose at which points of the lifecycle of a span a record should be emitted.
+    ///
+    /// It defaults to `FmtSpan::NEW | FmtSpan::CLOSE`, i.e. a `[SPAN - START]` record when
+    /// a span is created and a `[SPAN - END]` record when it is closed.
+    /// Use `FmtSpan::NONE` to only emit records for events.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::BunyanFormattingLayer;
+    /// use tracing_subscriber::fmt::format::FmtSpan;
+    ///
+    /// // A `[SPAN - ENTER]` and a `[SPAN - EXIT]` record every time a span is entered or exited.
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .span_events(FmtSpan::ACTIVE)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn span_events(mut self, span_events: FmtSpan) -> Self {
+        self.span_events = span_events;
+        self
+    }
+
+    /// Choose how to resolve a key set by more than one source (default fields,
+    /// event fields, span fields) in the same record.
+    ///
+    /// It defaults to [`KeyCollisionPolicy::SpanWins`].
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, KeyCollisionPolicy};
+    ///
+    /// // `info!(user_id = 1, ...)` inside a span with a `user_id = 2` field emits
+    /// // `"user_id": 1, "span.user_id": 2`.
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .key_collision_policy(KeyCollisionPolicy::Namespace)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn key_collision_policy(mut self, key_collision_policy: KeyCollisionPolicy) -> Self {
+        self.key_collision_policy = key_collision_policy;
+        self
+    }
+
+    /// Choose the order of the non-core fields of each record.
+    ///
+    /// Core Bunyan fields always come first. It defaults to [`FieldOrdering::Unordered`],
+    /// the cheapest option.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, FieldOrdering};
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .field_ordering(FieldOrdering::Alphabetical)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn field_ordering(mut self, field_ordering: FieldOrdering) -> Self {
+        self.field_ordering = field_ordering;
+        self
+    }
+
+    /// Redact the values of the fields matching `rule`, wherever they come from: default
+    /// fields, event fields or span fields.
+    ///
+    /// Rules are checked in the order they were added: the first matching rule wins.
+    /// See [`RedactionRule`] for more details.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, Redaction, RedactionRule};
+    ///
+    /// // `info!(password = "hunter2", api_token = "abcdef", ...)` emits
+    /// // `"password": "[REDACTED]", "api_token": "**cdef"`.
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .redact(RedactionRule::exact("password"))
+    ///     .redact(RedactionRule::glob("*token").with(Redaction::RevealLast(4)))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn redact(mut self, rule: RedactionRule) -> Self {
+        self.redaction_rules.push(rule);
+        self
+    }
+
+    /// Attach the identifiers of the span they belong to to span records and to events
+    /// emitted inside a span: `span_id`, `parent_span_id` and `root_span_id`.
+    ///
+    /// It defaults to [`SpanIds::Disabled`].
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, SpanIds};
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .span_ids(SpanIds::Generated)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn span_ids(mut self, span_ids: SpanIds) -> Self {
+        self.span_ids = span_ids;
+        self
+    }
+
+    /// Choose whether to attach the list of the spans they belong to to span records and to
+    /// events emitted inside a span, from the root to the leaf, as a `spans` array.
+    ///
+    /// Each span is emitted as `{"name": ..., "id": ..., "fields": {...}}`, with its own fields
+    /// only, while the top-level fields of the record merge the fields of all the spans.
+    /// Identifiers follow [`BunyanFormattingLayerBuilder::span_ids`], falling back to the
+    /// ones assigned by the registry.
+    ///
+    /// It defaults to `false`.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::BunyanFormattingLayer;
+    ///
+    /// // {..., "spans": [{"name": "http_request", "id": 1, "fields": {"method": "GET"}}, ...]}
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .span_list(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn span_list(mut self, span_list: bool) -> Self {
+        self.span_list = span_list;
+        self
+    }
+
+    /// Choose whether to attach the names of the spans they belong to to span records and to
+    /// events emitted inside a span, from the root to the leaf, as a `span_path` string, e.g.
+    /// `http_request/db_query`.
+    ///
+    /// It defaults to `false`.
+    pub fn span_path(mut self, span_path: bool) -> Self {
+        self.span_path = span_path;
+        self
+    }
+
+    /// Store the fields of spans in their [`JsonStorage`](crate::JsonStorage), as
+    /// [`JsonStorageLayer`](crate::JsonStorageLayer) does, so that it doesn't have to be
+    /// registered as well.
+    ///
+    /// Fields are stored and formatted in a single pass, and the storage is still available
+    /// to the layers registered after this one, as well as to [`SpanExt`](crate::SpanExt).
+    /// Registering `JsonStorageLayer` as well is harmless: whichever layer comes first stores
+    /// the fields, the other one reuses its storage.
+    ///
+    /// It defaults to `false`.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::BunyanFormattingLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .json_storage(true)
+    ///     .build()
+    ///     .unwrap();
+    /// let subscriber = Registry::default().with(formatting_layer);
+    /// ```
+    pub fn json_storage(mut self, json_storage: bool) -> Self {
+        self.json_storage = json_storage;
+        self
+    }
+
+    /// Choose whether to attach the OpenTelemetry identifiers of the span they belong to
+    /// to span records and to events emitted inside a span: `trace_id`, `span_id` and
+    /// `trace_flags`.
+    ///
+    /// They are read from the context stored by `tracing-opentelemetry`'s `OpenTelemetryLayer`,
+    /// which must be registered as well. Spans it doesn't track get no identifiers, while
+    /// `trace_flags` is only emitted once the sampling decision for a span is known (e.g.
+    /// after a child span has been created), unless it can be inherited from its parent.
+    ///
+    /// The context is only found if the application uses the same version of
+    /// `tracing-opentelemetry` as this crate (0.31): the context stored by other versions
+    /// is ignored.
+    ///
+    /// It defaults to `true`. If [`BunyanFormattingLayerBuilder::span_ids`] is enabled as well,
+    /// the OpenTelemetry span identifier is emitted as `otel.span_id` instead.
+    #[cfg(feature = "opentelemetry")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "opentelemetry")))]
+    pub fn opentelemetry_ids(mut self, opentelemetry_ids: bool) -> Self {
+        self.opentelemetry_ids = opentelemetry_ids;
+        self
+    }
+
+    /// Choose what happens to the records which can't be serialized or written, e.g.
+    /// because the disk is full.
+    ///
+    /// They are silently dropped by default, see [`ErrorHandler`] for the alternatives.
+    /// Failures are counted in any case, see [`BunyanFormattingLayer::error_counters`].
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, ErrorHandler};
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .error_handler(ErrorHandler::callback(|error| eprintln!("{}", error)))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn error_handler(mut self, error_handler: ErrorHandler) -> Self {
+        self.error_handler = error_handler;
+        self
+    }
+
+    /// Choose the Bunyan level of the records about a span or an event, e.g. to emit `fatal`
+    /// records, which have no `tracing` equivalent.
+    ///
+    /// By default, `tracing` levels are mapped to Bunyan's `trace` to `error` levels.
+    /// Events can override their level with the reserved `bunyan.level` field, set to the name
+    /// of a Bunyan level (e.g. `bunyan.level = "fatal"`) or to its numeric value: it takes
+    /// precedence over the mapping and is never emitted as a field.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, BunyanLevel};
+    ///
+    /// // `error!(target: "fatal", ...)` emits a record with level 60.
+    /// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+    ///     .level_mapping(|metadata| match metadata.target() {
+    ///         "fatal" => BunyanLevel::FATAL,
+    ///         _ => BunyanLevel::from(metadata.level()),
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn level_mapping(
+        mut self,
+        level_mapping: impl Fn(&Metadata<'_>) -> BunyanLevel + Send + Sync + 'static,
+    ) -> Self {
+        self.level_mapping = Some(Box::new(level_mapping));
+        self
+    }
+
+    /// Validate the configuration and build the [`BunyanFormattingLayer`].
+    pub fn build(mut self) -> Result<BunyanFormattingLayer<W>, BuildError> {
+        if let Some(field) = self
+            .skip_fields
+            .iter()
+            .find(|field| BUNYAN_REQUIRED_FIELDS.contains(&field.as_str()))
+        {
+            return Err(BuildError::SkippedCoreField(field.to_owned()));
+        }
+        if let Some(field) = self
+            .default_fields
+            .iter()
+            .map(|(field, _)| field)
+            .find(|field| BUNYAN_REQUIRED_FIELDS.contains(&field.as_str()))
+        {
+            return Err(BuildError::ReservedDefaultField(field.to_owned()));
+        }
+        let redactor = Redactor::new(std::mem::take(&mut self.redaction_rules))?;
+        let mut layer = self.build_unchecked();
+        layer.redactor = redactor;
+        Ok(layer)
+    }
+
+    /// Build the [`BunyanFormattingLayer`] without validating the configuration.
+    ///
+    /// Used by the legacy constructors, which silently ignore reserved default fields.
+    /// Redaction rules are only compiled by [`BunyanFormattingLayerBuilder::build`].
+    pub(crate) fn build_unchecked(self) -> BunyanFormattingLayer<W> {
+        let mut skip_fields = HashSet::with_capacity(self.skip_fields.len());
+        skip_fields.extend(self.skip_fields);
+        let mut layer = BunyanFormattingLayer {
+            sink: self.make_writer,
+            name: self.name,
+            pid: self.pid.unwrap_or_else(std::process::id),
+            hostname: self.hostname.unwrap_or_else(default_hostname),
+            bunyan_version: self.bunyan_version,
+            default_fields: self.default_fields,
+            skip_fields,
+            source_location: self.source_location,
+            timer: self.timer,
+            span_events: self.span_events,
+            key_collision_policy: self.key_collision_policy,
+            field_ordering: self.field_ordering,
+            redactor: Redactor::default(),
+            span_ids: self.span_ids,
+            span_list: self.span_list,
+            span_path: self.span_path,
+            json_storage: self.json_storage,
+            #[cfg(feature = "opentelemetry")]
+            opentelemetry_ids: self.opentelemetry_ids,
+            reserved_fields: Vec::new(),
+            error_handler: self.error_handler,
+            error_counters: Default::default(),
+            level_mapping: self.level_mapping,
+        };
+        layer.update_reserved_fields();
+        layer
+    }
+}
+
+#[cfg(feature = "hostname")]
+fn default_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+#[cfg(not(feature = "hostname"))]
+fn default_hostname() -> String {
+    String::new()
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The step at which a record was lost, see [`FormattingError`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FormattingErrorKind {
+    /// The record could not be serialized, e.g. because the `Debug` implementation of a field
+    /// returned an error.
+    Serialization,
+    /// The serialized record could not be written, e.g. because the disk is full or the pipe
+    /// has been closed.
+    Io,
+}
+
+/// A record that could not be emitted by [`BunyanFormattingLayer`](crate::BunyanFormattingLayer).
+#[derive(Debug)]
+pub struct FormattingError<'a> {
+    kind: FormattingErrorKind,
+    error: &'a io::Error,
+    record: Option<&'a [u8]>,
+    metadata: Option<&'a Metadata<'a>>,
+}
+
+impl<'a> FormattingError<'a> {
+    /// The step at which the record was lost.
+    pub fn kind(&self) -> FormattingErrorKind {
+        self.kind
+    }
+
+    /// The underlying error.
+    pub fn error(&self) -> &'a io::Error {
+        self.error
+    }
+
+    /// The serialized record, including its trailing new line, if it could be serialized,
+    /// i.e. for [`FormattingErrorKind::Io`] errors.
+    pub fn record(&self) -> Option<&'a [u8]> {
+        self.record
+    }
+
+    /// The metadata of the span or event the record was about.
+    ///
+    /// It is not available for the records written by the background thread of
+    /// [`NonBlocking`](crate::NonBlocking), nor for its failed flushes.
+    pub fn metadata(&self) -> Option<&'a Metadata<'a>> {
+        self.metadata
+    }
+}
+
+impl fmt::Display for FormattingError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = match self.kind {
+            FormattingErrorKind::Serialization => "serialize",
+            FormattingErrorKind::Io => "write",
+        };
+        match self.metadata {
+            Some(metadata) => write!(
+                f,
+                "Failed to {} a record for {} ({}): {}",
+                step,
+                metadata.name(),
+                metadata.target(),
+                self.error
+            ),
+            None => write!(f, "Failed to {} a record: {}", step, self.error),
+        }
+    }
+}
+
+type Callback = dyn Fn(&FormattingError<'_>) + Send + Sync;
+
+/// What [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) does with the records it fails
+/// to serialize or to write.
+///
+/// Failures are counted in any case, see [`ErrorCounters`].
+///
+/// ```rust
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, ErrorHandler};
+///
+/// // Write the records that can't be written to stdout to stderr instead.
+/// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+///     .error_handler(ErrorHandler::fallback(std::io::stderr))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct ErrorHandler {
+    callback: Option<Arc<Callback>>,
+}
+
+impl ErrorHandler {
+    /// Drop the records silently. This is the default.
+    pub fn ignore() -> Self {
+        Self::default()
+    }
+
+    /// Call `callback` for each record that could not be emitted.
+    ///
+    /// The callback runs on the thread emitting the record: avoid emitting spans or events
+    /// from it, they would be handled by the same failing layer.
+    pub fn callback(callback: impl Fn(&FormattingError<'_>) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Some(Arc::new(callback)),
+        }
+    }
+
+    /// Write the records that could not be written to another writer, e.g. `std::io::stderr`.
+    ///
+    /// A line describing the error is written instead for the records that could not be
+    /// serialized. Errors of the fallback writer are ignored.
+    pub fn fallback<M>(make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self::callback(move |error| {
+            let mut writer = match error.metadata() {
+                Some(metadata) => make_writer.make_writer_for(metadata),
+                None => make_writer.make_writer(),
+            };
+            let _ = match error.record() {
+                Some(record) => writer.write_all(record),
+                None => writeln!(writer, "{}", error),
+            };
+        })
+    }
+
+    /// Panic as soon as a record can't be emitted. Mostly useful in tests.
+    pub fn panic() -> Self {
+        Self::callback(|error| panic!("{}", error))
+    }
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let handler = if self.callback.is_some() {
+            "Callback"
+        } else {
+            "Ignore"
+        };
+        f.debug_tuple("ErrorHandler").field(&handler).finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    serialization_errors: AtomicU64,
+    io_errors: AtomicU64,
+}
+
+/// A handle to the number of records a
+/// [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) failed to emit, obtained with
+/// [`BunyanFormattingLayer::error_counters`](crate::BunyanFormattingLayer::error_counters),
+/// or the background thread of a [`NonBlocking`](crate::NonBlocking) writer failed to write,
+/// obtained with [`NonBlocking::error_counters`](crate::NonBlocking::error_counters).
+///
+/// It can be kept after the layer has been moved into a subscriber.
+///
+/// ```rust
+/// use tracing_bunyan_formatter::BunyanFormattingLayer;
+///
+/// let formatting_layer = BunyanFormattingLayer::new("test".into(), std::io::stdout);
+/// let counters = formatting_layer.error_counters();
+/// // [...] Register the layer and emit some records.
+/// assert_eq!(counters.io_errors(), 0);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ErrorCounters {
+    counters: Arc<Counters>,
+}
+
+impl ErrorCounters {
+    /// The number of records that could not be serialized.
+    pub fn serialization_errors(&self) -> u64 {
+        self.counters.serialization_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of serialized records that could not be written, including failed flushes.
+    pub fn io_errors(&self) -> u64 {
+        self.counters.io_errors.load(Ordering::Relaxed)
+    }
+}
+
+/// Count a record that could not be emitted and hand it over to the [`ErrorHandler`].
+pub(crate) fn report(
+    handler: &ErrorHandler,
+    counters: &ErrorCounters,
+    kind: FormattingErrorKind,
+    error: &io::Error,
+    record: Option<&[u8]>,
+    metadata: Option<&Metadata<'_>>,
+) {
+    let counter = match kind {
+        FormattingErrorKind::Serialization => &counters.counters.serialization_errors,
+        FormattingErrorKind::Io => &counters.counters.io_errors,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    if let Some(callback) = &handler.callback {
+        callback(&FormattingError {
+            kind,
+            error,
+            record,
+            metadata,
+        });
+    }
+}
@@ -0,0 +1,283 @@
+use crate::fields::Resolver;
+use crate::level::{BunyanLevel, LEVEL_FIELD};
+use crate::redaction::Redactor;
+use crate::storage_layer::error_to_json;
+use ahash::HashSet;
+use serde::ser::{Error as _, Serialize, SerializeMap};
+use serde_json::Value;
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use tracing::field::{Field, Visit};
+use tracing::Event;
+
+/// Extract the fields of an event used for core Bunyan fields: its `message`, appended to
+/// a buffer, and the level set by the reserved `bunyan.level` field.
+///
+/// Only string and `Debug` values are used as the message, as `JsonStorage` does.
+pub(crate) struct CoreFieldsVisitor<'b> {
+    buffer: &'b mut String,
+    found_message: bool,
+    level: Option<BunyanLevel>,
+}
+
+impl<'b> CoreFieldsVisitor<'b> {
+    pub(crate) fn new(buffer: &'b mut String) -> Self {
+        Self {
+            buffer,
+            found_message: false,
+            level: None,
+        }
+    }
+
+    /// Check if the event has a message.
+    pub(crate) fn found_message(&self) -> bool {
+        self.found_message
+    }
+
+    /// The level set by the `bunyan.level` field, if it is a valid level.
+    pub(crate) fn level(&self) -> Option<BunyanLevel> {
+        self.level
+    }
+}
+
+impl Visit for CoreFieldsVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == LEVEL_FIELD {
+            self.level = u16::try_from(value).ok().map(BunyanLevel::custom);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == LEVEL_FIELD {
+            self.level = u16::try_from(value).ok().map(BunyanLevel::custom);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => {
+                self.buffer.push_str(value);
+                self.found_message = true;
+            }
+            LEVEL_FIELD => self.level = BunyanLevel::from_name(value),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.buffer, "{:?}", value);
+            self.found_message = true;
+        }
+    }
+}
+
+/// Check if the fields of `event` can be serialized straight from the `Visit` callbacks by
+/// [`EventFieldsSerializer`]: they are all recorded, under distinct names.
+///
+/// Otherwise, the last value recorded for a name has to win, as in `JsonStorage`, and fields
+/// declared without a value (e.g. `field::Empty`) must not take part in key collisions.
+pub(crate) fn records_distinct_fields(event: &Event<'_>) -> bool {
+    let fields = event.metadata().fields();
+    let name = |field: &Field| {
+        let name = field.name();
+        name.strip_prefix("r#").unwrap_or(name)
+    };
+    let mut counter = RecordedFieldsCounter(0);
+    event.record(&mut counter);
+    counter.0 == fields.len()
+        && fields.iter().enumerate().all(|(i, field)| {
+            fields
+                .iter()
+                .take(i)
+                .all(|other| name(&other) != name(&field))
+        })
+}
+
+/// Count the fields recorded by an event.
+struct RecordedFieldsCounter(usize);
+
+impl Visit for RecordedFieldsCounter {
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {
+        self.0 += 1;
+    }
+}
+
+/// Serialize the fields of an event straight from the `Visit` callbacks, without building
+/// a `JsonStorage` first.
+///
+/// Fields are named and converted exactly as `JsonStorage` does, while their keys are
+/// resolved against the other fields of the record by a [`Resolver`].
+pub(crate) struct EventFieldsSerializer<'s, 'r, 'a, M> {
+    map_serializer: &'s mut M,
+    resolver: Resolver<'r, 'a>,
+    redactor: &'s Redactor,
+    skip_fields: &'s HashSet<String>,
+    result: Result<(), serde_json::Error>,
+}
+
+impl<'s, 'r, 'a, M> EventFieldsSerializer<'s, 'r, 'a, M>
+where
+    M: SerializeMap<Error = serde_json::Error>,
+{
+    pub(crate) fn new(
+        map_serializer: &'s mut M,
+        resolver: Resolver<'r, 'a>,
+        redactor: &'s Redactor,
+        skip_fields: &'s HashSet<String>,
+    ) -> Self {
+        Self {
+            map_serializer,
+            resolver,
+            redactor,
+            skip_fields,
+            result: Ok(()),
+        }
+    }
+
+    /// The first error raised while serializing the fields, if any.
+    pub(crate) fn finish(self) -> Result<(), serde_json::Error> {
+        self.result
+    }
+
+    /// Serialize a field, unless it lost a key collision or it is skipped.
+    ///
+    /// `to_json` is only called if redaction rules have to be checked against the value.
+    fn serialize<V>(
+        &mut self,
+        name: &str,
+        value: &V,
+        to_json: impl FnOnce() -> Result<Value, serde_json::Error>,
+    ) where
+        V: Serialize + ?Sized,
+    {
+        if self.result.is_err() {
+            return;
+        }
+        let key = match self.resolver.key_for(name) {
+            // Fields moved to a namespaced key are skipped by their name as well.
+            Some(key)
+                if !self.skip_fields.contains(key.as_ref()) && !self.skip_fields.contains(name) =>
+            {
+                key
+            }
+            _ => return,
+        };
+        self.result = if self.redactor.is_empty() {
+            self.map_serializer.serialize_entry(key.as_ref(), value)
+        } else {
+            to_json().and_then(|value| {
+                self.map_serializer
+                    .serialize_entry(key.as_ref(), &self.redactor.redact(name, &value))
+            })
+        };
+    }
+}
+
+/// Serialize a `Debug` value as a string, without an intermediate `String`.
+///
+/// A `Debug` implementation returning an error fails the serialization of the record.
+struct DebugValue<'v>(&'v dyn fmt::Debug);
+
+impl Serialize for DebugValue<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let failed = Cell::new(false);
+        let serialized = serializer.collect_str(&FallibleDebug {
+            value: self.0,
+            failed: &failed,
+        })?;
+        if failed.get() {
+            return Err(S::Error::custom(
+                "the Debug implementation of a field returned an error",
+            ));
+        }
+        Ok(serialized)
+    }
+}
+
+/// Format a `Debug` value, recording its errors instead of returning them: `serde_json`
+/// panics when a `Display` implementation fails on its own.
+struct FallibleDebug<'v> {
+    value: &'v dyn fmt::Debug,
+    failed: &'v Cell<bool>,
+}
+
+impl fmt::Display for FallibleDebug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if fmt::Debug::fmt(self.value, f).is_err() {
+            self.failed.set(true);
+        }
+        Ok(())
+    }
+}
+
+impl<M> Visit for EventFieldsSerializer<'_, '_, '_, M>
+where
+    M: SerializeMap<Error = serde_json::Error>,
+{
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.serialize(field.name(), &value, || Ok(Value::from(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.serialize(field.name(), &value, || Ok(Value::from(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.serialize(field.name(), &value, || Ok(Value::from(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.serialize(field.name(), &value, || Ok(Value::from(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.serialize(field.name(), value, || Ok(Value::from(value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let name = match field.name() {
+            // Skip fields that are actually log metadata that have already been handled
+            name if name.starts_with("log.") => return,
+            name => name.strip_prefix("r#").unwrap_or(name),
+        };
+        self.serialize(name, &DebugValue(value), || {
+            serde_json::to_value(DebugValue(value))
+        });
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        let name = match field.name() {
+            name if name.starts_with("log.") => return,
+            name => name.strip_prefix("r#").unwrap_or(name),
+        };
+        let value = error_to_json(value);
+        self.serialize(name, &value, || Ok(value.clone()));
+    }
+
+    #[cfg(all(tracing_unstable, feature = "valuable"))]
+    #[cfg_attr(docsrs, doc(cfg(all(tracing_unstable, feature = "valuable"))))]
+    fn record_value(&mut self, field: &Field, value: valuable::Value<'_>) {
+        let serializable = valuable_serde::Serializable::new(value);
+
+        match serde_json::to_value(serializable) {
+            Ok(json_value) => {
+                self.serialize(field.name(), &json_value, || Ok(json_value.clone()));
+            }
+            Err(error) => {
+                tracing::debug!(
+                    // The parent span may be the one with this
+                    // unserializable field value. If so logging an event
+                    // under this parent span might trigger it field value
+                    // to be serialized again, causing an infinite loop.
+                    // Avoid this by explicitly setting the parent span to `None`.
+                    parent: None,
+                    ?error,
+                    field_name = field.name(),
+                    "serde_json serialization error while recording valuable field."
+                );
+            }
+        }
+    }
+}
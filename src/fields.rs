@@ -0,0 +1,346 @@
+use crate::storage_layer::{self, JsonStorage};
+use serde_json::Value;
+use std::borrow::Cow;
+use tracing::field::FieldSet;
+
+/// How [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) resolves a key that is set
+/// by more than one source (default fields, event fields and span fields) in the same record.
+///
+/// Whatever the policy, every record has unique keys.
+/// Keys generated by the layer itself (the core Bunyan fields, `target`, `line`, `file`
+/// and `src`) always win against user-provided fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyCollisionPolicy {
+    /// Span fields win over event fields, which win over default fields.
+    ///
+    /// This is the default: it matches how most JSON parsers used to interpret the records
+    /// emitted before this policy was introduced, where later duplicate keys took precedence.
+    #[default]
+    SpanWins,
+    /// Event fields win over span fields, which win over default fields.
+    EventWins,
+    /// Default fields win over event fields, which win over span fields.
+    DefaultWins,
+    /// Event fields win over span fields, which win over default fields, but the losing
+    /// values are kept under a namespaced key: `span.<key>`, `default.<key>` or
+    /// `event.<key>` (when colliding with a key generated by the layer).
+    ///
+    /// If the namespaced key is taken as well, the losing value is dropped.
+    Namespace,
+}
+
+impl KeyCollisionPolicy {
+    fn rank(self, source: FieldSource) -> u8 {
+        match (self, source) {
+            (KeyCollisionPolicy::SpanWins, FieldSource::Span) => 3,
+            (KeyCollisionPolicy::SpanWins, FieldSource::Event) => 2,
+            (KeyCollisionPolicy::DefaultWins, FieldSource::Default) => 3,
+            (KeyCollisionPolicy::DefaultWins, FieldSource::Event) => 2,
+            (KeyCollisionPolicy::EventWins | KeyCollisionPolicy::Namespace, FieldSource::Event) => {
+                3
+            }
+            (KeyCollisionPolicy::EventWins | KeyCollisionPolicy::Namespace, FieldSource::Span) => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// The order of the non-core fields of the records emitted by
+/// [`BunyanFormattingLayer`](crate::BunyanFormattingLayer).
+///
+/// Core Bunyan fields (`v`, `name`, `msg`, `level`, ...) always come first, followed by
+/// the call site (`target`, `line`, `file` or `src`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldOrdering {
+    /// No guarantee: the order can change between runs and between records of the same
+    /// call site.
+    ///
+    /// This is the default, and the cheapest option.
+    #[default]
+    Unordered,
+    /// Default fields, in the order they were configured, then event fields, then span
+    /// fields, in the order they were declared at their call site (from the root span to
+    /// the current one).
+    ///
+    /// Span fields that are not declared by any call site (e.g. `elapsed_milliseconds`)
+    /// come last, sorted by key.
+    Declaration,
+    /// Sorted by key.
+    Alphabetical,
+}
+
+/// Where the value of a field comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FieldSource {
+    Default,
+    Event,
+    Span,
+}
+
+impl FieldSource {
+    fn namespace(self) -> &'static str {
+        match self {
+            FieldSource::Default => "default",
+            FieldSource::Event => "event",
+            FieldSource::Span => "span",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SourceValues<'a> {
+    /// Key-value pairs with unique keys, e.g. the default fields.
+    List(&'a [(String, Value)]),
+    /// The values of a `JsonStorage`, walked without materializing them.
+    Storage(&'a JsonStorage<'a>),
+    /// The fields declared at a call site, whose values are visited by the caller instead
+    /// (see [`MergedField::Declared`]).
+    Declared(&'a FieldSet),
+}
+
+/// The fields of a record coming from the same [`FieldSource`].
+pub(crate) struct Source<'a> {
+    kind: FieldSource,
+    values: SourceValues<'a>,
+    /// Keys to leave out, e.g. `message` for events, which is already used for `msg`.
+    ignored: &'static [&'static str],
+    /// The order the fields should be emitted in, if it matters.
+    order: Option<Vec<(&'a str, &'a Value)>>,
+}
+
+impl<'a> Source<'a> {
+    pub(crate) fn list(kind: FieldSource, values: &'a [(String, Value)]) -> Self {
+        Self::new(kind, SourceValues::List(values))
+    }
+
+    pub(crate) fn storage(kind: FieldSource, storage: &'a JsonStorage<'a>) -> Self {
+        Self::new(kind, SourceValues::Storage(storage))
+    }
+
+    /// Fields declared at a call site (e.g. the fields of an event), whose values are
+    /// serialized by the caller while visiting them, without going through a `Value`.
+    ///
+    /// It can't be used with [`FieldOrdering::Alphabetical`], since visited fields can't be sorted.
+    pub(crate) fn declared(kind: FieldSource, fields: &'a FieldSet) -> Self {
+        Self::new(kind, SourceValues::Declared(fields))
+    }
+
+    fn new(kind: FieldSource, values: SourceValues<'a>) -> Self {
+        Self {
+            kind,
+            values,
+            ignored: &[],
+            order: None,
+        }
+    }
+
+    /// Leave out the fields named `keys`.
+    pub(crate) fn ignoring(mut self, keys: &'static [&'static str]) -> Self {
+        self.ignored = keys;
+        self
+    }
+
+    /// Emit the fields in the given order.
+    ///
+    /// `order` must contain all the fields of the source.
+    pub(crate) fn ordered(mut self, order: Vec<(&'a str, &'a Value)>) -> Self {
+        self.order = Some(order);
+        self
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        if self.ignored.contains(&key) {
+            return false;
+        }
+        match self.values {
+            SourceValues::List(values) => values.iter().any(|(k, _)| k == key),
+            SourceValues::Storage(storage) => storage.get(key).is_some(),
+            // Raw identifiers (e.g. `r#type`) are recorded without their `r#` prefix.
+            SourceValues::Declared(fields) => fields.iter().any(|field| {
+                let name = field.name();
+                name == key || name.strip_prefix("r#") == Some(key)
+            }),
+        }
+    }
+
+    /// The fields of the source, none for [`Source::declared`].
+    fn iter(&self) -> impl Iterator<Item = (&'a str, &'a Value)> + '_ {
+        let iter = match (&self.order, self.values) {
+            (Some(order), _) => SourceIter::Ordered(order.iter()),
+            (None, SourceValues::List(values)) => SourceIter::List(values.iter()),
+            (None, SourceValues::Storage(storage)) => SourceIter::Storage(storage.iter()),
+            (None, SourceValues::Declared(_)) => SourceIter::Ordered([].iter()),
+        };
+        iter.filter(move |(key, _)| !self.ignored.contains(key))
+    }
+}
+
+enum SourceIter<'s, 'a> {
+    Ordered(std::slice::Iter<'s, (&'a str, &'a Value)>),
+    List(std::slice::Iter<'a, (String, Value)>),
+    Storage(storage_layer::Iter<'a, 'a>),
+}
+
+impl<'a> Iterator for SourceIter<'_, 'a> {
+    type Item = (&'a str, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SourceIter::Ordered(iter) => iter.next().copied(),
+            SourceIter::List(iter) => iter.next().map(|(key, value)| (key.as_str(), value)),
+            SourceIter::Storage(iter) => iter.next(),
+        }
+    }
+}
+
+/// The non-core fields of a record, merged from all their sources according to a
+/// [`KeyCollisionPolicy`].
+///
+/// A key belongs to the highest-ranked source that contains it, which is checked against
+/// the sources themselves: no intermediate collection is built, unless fields have to be
+/// sorted according to [`FieldOrdering::Alphabetical`].
+pub(crate) struct MergedFields<'a> {
+    policy: KeyCollisionPolicy,
+    ordering: FieldOrdering,
+    /// Keys generated by the layer: colliding fields will never use them.
+    reserved: &'a [&'a str],
+    sources: [Option<Source<'a>>; 3],
+}
+
+impl<'a> MergedFields<'a> {
+    pub(crate) fn new(
+        policy: KeyCollisionPolicy,
+        ordering: FieldOrdering,
+        reserved: &'a [&'a str],
+    ) -> Self {
+        Self {
+            policy,
+            ordering,
+            reserved,
+            sources: [None, None, None],
+        }
+    }
+
+    /// Add a source of fields.
+    ///
+    /// Fields are emitted in the order their sources were added.
+    pub(crate) fn push(&mut self, source: Source<'a>) {
+        if let Some(slot) = self.sources.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(source);
+        }
+    }
+
+    fn sources(&self) -> impl Iterator<Item = &Source<'a>> {
+        self.sources.iter().flatten()
+    }
+
+    /// Check if `key` belongs to a source of kind `kind`.
+    fn wins(&self, key: &str, kind: FieldSource) -> bool {
+        let rank = self.policy.rank(kind);
+        !self.reserved.contains(&key)
+            && !self
+                .sources()
+                .any(|other| self.policy.rank(other.kind) > rank && other.contains(key))
+    }
+
+    fn is_taken(&self, key: &str) -> bool {
+        self.reserved.contains(&key) || self.sources().any(|source| source.contains(key))
+    }
+
+    /// Resolve collisions, calling `f` for each resulting field: each key is emitted at most once.
+    ///
+    /// For [`Source::declared`] sources, `f` receives a [`Resolver`] to resolve the keys of
+    /// the fields it visits itself.
+    pub(crate) fn try_for_each<E>(
+        &self,
+        mut f: impl for<'r> FnMut(MergedField<'r, 'a>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if self.ordering == FieldOrdering::Alphabetical {
+            let mut resolved: Vec<(Cow<'a, str>, &'a str, &'a Value)> = Vec::new();
+            self.resolve(|field| {
+                if let MergedField::Value { key, name, value } = field {
+                    resolved.push((key, name, value));
+                }
+                Ok::<(), E>(())
+            })?;
+            resolved.sort_unstable_by(|(a, _, _), (b, _, _)| a.cmp(b));
+            return resolved
+                .into_iter()
+                .try_for_each(|(key, name, value)| f(MergedField::Value { key, name, value }));
+        }
+        self.resolve(f)
+    }
+
+    fn resolve<E>(
+        &self,
+        mut f: impl for<'r> FnMut(MergedField<'r, 'a>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut namespaced: Vec<String> = Vec::new();
+        for source in self.sources() {
+            if let SourceValues::Declared(_) = source.values {
+                f(MergedField::Declared(Resolver {
+                    fields: self,
+                    source,
+                    namespaced: &mut namespaced,
+                }))?;
+                continue;
+            }
+            for (name, value) in source.iter() {
+                if let Some(key) = self.key_for(source, name, &mut namespaced) {
+                    f(MergedField::Value { key, name, value })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The key of the field named `name` of `source` in the record, if it has to be emitted.
+    fn key_for<'n>(
+        &self,
+        source: &Source<'_>,
+        name: &'n str,
+        namespaced: &mut Vec<String>,
+    ) -> Option<Cow<'n, str>> {
+        if source.ignored.contains(&name) {
+            None
+        } else if self.wins(name, source.kind) {
+            Some(Cow::Borrowed(name))
+        } else if self.policy == KeyCollisionPolicy::Namespace {
+            let key = format!("{}.{}", source.kind.namespace(), name);
+            if self.is_taken(&key) || namespaced.contains(&key) {
+                return None;
+            }
+            namespaced.push(key.clone());
+            Some(Cow::Owned(key))
+        } else {
+            None
+        }
+    }
+}
+
+/// A field of a record, as resolved by [`MergedFields::try_for_each`].
+pub(crate) enum MergedField<'r, 'a> {
+    /// A field with its key in the record and its original name, which differ for fields
+    /// moved to a namespaced key.
+    Value {
+        key: Cow<'a, str>,
+        name: &'a str,
+        value: &'a Value,
+    },
+    /// The point where the fields of a [`Source::declared`] source have to be emitted.
+    Declared(Resolver<'r, 'a>),
+}
+
+/// Resolves the keys of the fields of a [`Source::declared`] source.
+pub(crate) struct Resolver<'r, 'a> {
+    fields: &'r MergedFields<'a>,
+    source: &'r Source<'a>,
+    namespaced: &'r mut Vec<String>,
+}
+
+impl Resolver<'_, '_> {
+    /// The key of the field named `name` in the record, if it has to be emitted.
+    pub(crate) fn key_for<'n>(&mut self, name: &'n str) -> Option<Cow<'n, str>> {
+        self.fields.key_for(self.source, name, self.namespaced)
+    }
+}
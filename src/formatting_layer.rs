@@ -1,16 +1,28 @@
-use crate::storage_layer::JsonStorage;
+use crate::buffers::with_buffers;
+use crate::builder::{BuildError, BunyanFormattingLayerBuilder};
+use crate::error_handler::{self, ErrorCounters, ErrorHandler};
+use crate::event_visitor::{records_distinct_fields, CoreFieldsVisitor, EventFieldsSerializer};
+use crate::fields::{
+    FieldOrdering, FieldSource, KeyCollisionPolicy, MergedField, MergedFields, Source,
+};
+use crate::level::{BunyanLevel, LEVEL_FIELD};
+#[cfg(feature = "opentelemetry")]
+use crate::otel;
+use crate::redaction::Redactor;
+use crate::sink::{Record, RecordFields, RecordSink, SinkError};
+use crate::storage_layer::{self, JsonStorage};
+use crate::timestamp::BunyanTime;
 use ahash::{HashSet, HashSetExt};
 use serde::ser::{Serialize, SerializeMap, Serializer};
 use serde_json::Value;
+use std::any::TypeId;
 use std::collections::HashMap;
-use std::fmt;
-use std::io::Write;
-use time::format_description::well_known::Rfc3339;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicU64, Ordering};
 use tracing::{Event, Id, Metadata, Subscriber};
-use tracing_core::metadata::Level;
 use tracing_core::span::Attributes;
-use tracing_log::AsLog;
-use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::{FmtSpan, Writer};
+use tracing_subscriber::fmt::time::FormatTime;
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::SpanRef;
 use tracing_subscriber::Layer;
@@ -23,59 +35,154 @@ const HOSTNAME: &str = "hostname";
 const PID: &str = "pid";
 const TIME: &str = "time";
 const MESSAGE: &str = "msg";
-const _SOURCE: &str = "src";
+const SOURCE: &str = "src";
 
-const BUNYAN_REQUIRED_FIELDS: [&str; 7] =
+/// Keys of the identifiers of the span a record belongs to, see [`SpanIds`].
+const SPAN_ID: &str = "span_id";
+const PARENT_SPAN_ID: &str = "parent_span_id";
+const ROOT_SPAN_ID: &str = "root_span_id";
+
+/// Keys of the spans a record belongs to, see [`BunyanFormattingLayerBuilder::span_list`]
+/// and [`BunyanFormattingLayerBuilder::span_path`].
+const SPANS: &str = "spans";
+const SPAN_PATH: &str = "span_path";
+
+pub(crate) const BUNYAN_REQUIRED_FIELDS: [&str; 7] =
     [BUNYAN_VERSION, LEVEL, NAME, HOSTNAME, PID, TIME, MESSAGE];
 
-/// Convert from log levels to Bunyan's levels.
-fn to_bunyan_level(level: &Level) -> u16 {
-    match level.as_log() {
-        log::Level::Error => 50,
-        log::Level::Warn => 40,
-        log::Level::Info => 30,
-        log::Level::Debug => 20,
-        log::Level::Trace => 10,
-    }
+/// By default, a record is emitted when a span is created and when it is closed.
+pub(crate) fn default_span_events() -> FmtSpan {
+    FmtSpan::NEW | FmtSpan::CLOSE
 }
 
+/// A custom mapping from the metadata of spans and events to Bunyan levels.
+pub(crate) type LevelMapping = Box<dyn Fn(&Metadata<'_>) -> BunyanLevel + Send + Sync>;
+
 /// This layer is exclusively concerned with formatting information using the [Bunyan format](https://github.com/trentm/node-bunyan).
 /// It relies on the upstream `JsonStorageLayer` to get access to the fields attached to
-/// each span.
-#[derive(Default)]
-pub struct BunyanFormattingLayer<W: for<'a> MakeWriter<'a> + 'static> {
-    make_writer: W,
-    pid: u32,
-    hostname: String,
-    bunyan_version: u8,
-    name: String,
-    default_fields: HashMap<String, Value>,
-    skip_fields: HashSet<String>,
+/// each span: without it, or for the spans it filters out, records carry no span fields.
+///
+/// Alternatively, it can store the fields of spans itself, see
+/// [`BunyanFormattingLayerBuilder::json_storage`].
+pub struct BunyanFormattingLayer<W: RecordSink> {
+    pub(crate) sink: W,
+    pub(crate) pid: u32,
+    pub(crate) hostname: String,
+    pub(crate) bunyan_version: u8,
+    pub(crate) name: String,
+    pub(crate) default_fields: Vec<(String, Value)>,
+    pub(crate) skip_fields: HashSet<String>,
+    pub(crate) source_location: SourceLocation,
+    pub(crate) timer: Box<dyn FormatTime + Send + Sync>,
+    pub(crate) span_events: FmtSpan,
+    pub(crate) key_collision_policy: KeyCollisionPolicy,
+    pub(crate) field_ordering: FieldOrdering,
+    pub(crate) redactor: Redactor,
+    pub(crate) span_ids: SpanIds,
+    pub(crate) span_list: bool,
+    pub(crate) span_path: bool,
+    pub(crate) json_storage: bool,
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) opentelemetry_ids: bool,
+    pub(crate) reserved_fields: Vec<&'static str>,
+    pub(crate) error_handler: ErrorHandler,
+    pub(crate) error_counters: ErrorCounters,
+    pub(crate) level_mapping: Option<LevelMapping>,
 }
 
-/// This error will be returned in [`BunyanFormattingLayer::skip_fields`] if trying to skip a core field.
-#[non_exhaustive]
-#[derive(Debug)]
-pub struct SkipFieldError(String);
-
-impl fmt::Display for SkipFieldError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} is a core field in the bunyan log format, it can't be skipped",
-            &self.0
-        )
+impl<W: RecordSink + Default> Default for BunyanFormattingLayer<W> {
+    fn default() -> Self {
+        let mut layer = Self {
+            sink: W::default(),
+            pid: 0,
+            hostname: String::new(),
+            bunyan_version: 0,
+            name: String::new(),
+            default_fields: Vec::new(),
+            skip_fields: HashSet::new(),
+            source_location: SourceLocation::default(),
+            timer: Box::new(BunyanTime::default()),
+            span_events: default_span_events(),
+            key_collision_policy: KeyCollisionPolicy::default(),
+            field_ordering: FieldOrdering::default(),
+            redactor: Redactor::default(),
+            span_ids: SpanIds::default(),
+            span_list: false,
+            span_path: false,
+            json_storage: false,
+            #[cfg(feature = "opentelemetry")]
+            opentelemetry_ids: true,
+            reserved_fields: Vec::new(),
+            error_handler: ErrorHandler::default(),
+            error_counters: ErrorCounters::default(),
+            level_mapping: None,
+        };
+        layer.update_reserved_fields();
+        layer
     }
 }
 
-impl std::error::Error for SkipFieldError {}
+/// Controls how the call site of a record is emitted by [`BunyanFormattingLayer`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SourceLocation {
+    /// Emit `target`, `line` and `file` as top-level keys of the record.
+    ///
+    /// This is the default, for backwards compatibility.
+    #[default]
+    Flat,
+    /// Emit `target` as a top-level key and nest the call site under a `src` object
+    /// (`{"file": ..., "line": ..., "func": ...}`), as described in the
+    /// [Bunyan format](https://github.com/trentm/node-bunyan#src).
+    ///
+    /// `func` is populated with the module path of the call site.
+    Nested,
+}
+
+/// Controls whether records carry the identifiers of the span they belong to, and where
+/// they come from.
+///
+/// When enabled, span records and events emitted inside a span get a `span_id` field,
+/// a `parent_span_id` field (unless the span is a root) and a `root_span_id` field,
+/// which are enough to rebuild the tree of spans from the stream of records.
+/// For an event, they identify the span it was emitted in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanIds {
+    /// No identifiers are emitted.
+    ///
+    /// This is the default.
+    #[default]
+    Disabled,
+    /// Use the [`Id`]s assigned by the registry.
+    ///
+    /// They are cheap, but only guaranteed to be unique among the spans open at the same
+    /// time: the id of a closed span may be reused by a later one.
+    Registry,
+    /// Use identifiers generated when spans are created, which are never reused during
+    /// the lifetime of the process.
+    Generated,
+}
+
+/// The fields declared or recorded on a span itself, without the ones inherited from its
+/// parents, stored in its extensions when [`BunyanFormattingLayerBuilder::span_list`] is enabled.
+pub(crate) struct OwnFields(pub(crate) JsonStorage<'static>);
+
+/// The identifier generated for a span when using [`SpanIds::Generated`], stored in its extensions.
+struct GeneratedSpanId(u64);
+
+impl GeneratedSpanId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
 
-impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
+impl<W: RecordSink> BunyanFormattingLayer<W> {
     /// Create a new `BunyanFormattingLayer`.
     ///
     /// You have to specify:
     /// - a `name`, which will be attached to all formatted records according to the [Bunyan format](https://github.com/trentm/node-bunyan#log-record-fields);
-    /// - a `make_writer`, which will be used to get a `Write` instance to write formatted records to.
+    /// - a `make_writer`, which will be used to get a `Write` instance to write formatted records to,
+    ///   or any other [`RecordSink`].
     ///
     /// ## Using stdout
     ///
@@ -116,18 +223,24 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
         make_writer: W,
         default_fields: HashMap<String, Value>,
     ) -> Self {
-        Self {
-            make_writer,
-            name,
-            pid: std::process::id(),
-            #[cfg(feature = "hostname")]
-            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
-            #[cfg(not(feature = "hostname"))]
-            hostname: Default::default(),
-            bunyan_version: 0,
-            default_fields,
-            skip_fields: HashSet::new(),
-        }
+        BunyanFormattingLayerBuilder::new(name, make_writer)
+            .default_fields(default_fields)
+            .build_unchecked()
+    }
+
+    /// Create a [`BunyanFormattingLayerBuilder`], to configure all aspects of the layer
+    /// before building it.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::BunyanFormattingLayer;
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+    ///     .pid(42)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder(name: String, make_writer: W) -> BunyanFormattingLayerBuilder<W> {
+        BunyanFormattingLayerBuilder::new(name, make_writer)
     }
 
     /// Add fields to skip when formatting with this layer.
@@ -143,7 +256,7 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
     ///     .skip_fields(skipped_fields.into_iter())
     ///     .expect("One of the specified fields cannot be skipped");
     /// ```
-    pub fn skip_fields<Fields, Field>(mut self, fields: Fields) -> Result<Self, SkipFieldError>
+    pub fn skip_fields<Fields, Field>(mut self, fields: Fields) -> Result<Self, BuildError>
     where
         Fields: Iterator<Item = Field>,
         Field: Into<String>,
@@ -151,7 +264,7 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
         for field in fields {
             let field = field.into();
             if BUNYAN_REQUIRED_FIELDS.contains(&field.as_str()) {
-                return Err(SkipFieldError(field));
+                return Err(BuildError::SkippedCoreField(field));
             }
             self.skip_fields.insert(field);
         }
@@ -159,21 +272,109 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
         Ok(self)
     }
 
+    /// Choose how the call site of each record should be emitted.
+    ///
+    /// Use [`SourceLocation::Nested`] to get a Bunyan-compliant `src` object, which is
+    /// understood by `bunyan --src` and other Bunyan tooling.
+    /// Both the whole `src` object (`"src"`) and its sub-keys (e.g. `"src.func"`) can be
+    /// dropped using [`BunyanFormattingLayer::skip_fields`].
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, SourceLocation};
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::new("test".into(), std::io::stdout)
+    ///     .source_location(SourceLocation::Nested);
+    /// ```
+    pub fn source_location(mut self, source_location: SourceLocation) -> Self {
+        self.source_location = source_location;
+        self.update_reserved_fields();
+        self
+    }
+
+    /// Set the timer used to populate the `time` field of each record.
+    ///
+    /// It defaults to [`BunyanTime::utc`]. Any [`FormatTime`] implementation can be used,
+    /// but it should produce an [RFC 3339](https://datatracker.ietf.org/doc/html/rfc3339)
+    /// timestamp to be understood by Bunyan tooling.
+    /// If the timer fails, the record falls back to the default timer.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, BunyanTime, TimestampPrecision};
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::new("test".into(), std::io::stdout)
+    ///     .timer(BunyanTime::utc().precision(TimestampPrecision::Micros));
+    /// ```
+    pub fn timer(mut self, timer: impl FormatTime + Send + Sync + 'static) -> Self {
+        self.timer = Box::new(timer);
+        self
+    }
+
+    /// A handle to the number of records this layer failed to serialize or to write.
+    ///
+    /// See [`ErrorCounters`] for more details.
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.error_counters.clone()
+    }
+
+    /// Compute the keys generated by the layer, which user-provided fields can't override.
+    pub(crate) fn update_reserved_fields(&mut self) {
+        let mut reserved = BUNYAN_REQUIRED_FIELDS.to_vec();
+        reserved.push("target");
+        match self.source_location {
+            SourceLocation::Flat => reserved.extend(["line", "file"]),
+            SourceLocation::Nested => reserved.push(SOURCE),
+        }
+        if self.span_ids != SpanIds::Disabled {
+            reserved.extend([SPAN_ID, PARENT_SPAN_ID, ROOT_SPAN_ID]);
+        }
+        if self.span_list {
+            reserved.push(SPANS);
+        }
+        if self.span_path {
+            reserved.push(SPAN_PATH);
+        }
+        #[cfg(feature = "opentelemetry")]
+        if self.opentelemetry_ids {
+            reserved.extend([
+                otel::TRACE_ID,
+                self.opentelemetry_span_id_key(),
+                otel::TRACE_FLAGS,
+            ]);
+        }
+        self.reserved_fields = reserved;
+    }
+
+    /// The Bunyan level of the records about a span or an event.
+    fn bunyan_level(&self, meta: &Metadata<'_>) -> BunyanLevel {
+        match &self.level_mapping {
+            Some(level_mapping) => level_mapping(meta),
+            None => BunyanLevel::from(meta.level()),
+        }
+    }
+
+    /// Format the current time, falling back to the default format if `self.timer` fails.
+    fn format_time(&self, time: &mut String) -> Result<(), std::io::Error> {
+        if self.timer.format_time(&mut Writer::new(time)).is_err() {
+            time.clear();
+            BunyanTime::default()
+                .format_time(&mut Writer::new(time))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+        Ok(())
+    }
+
     fn serialize_bunyan_core_fields(
         &self,
         map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
-        message: &str,
-        level: &Level,
+        record: &Record<'_>,
     ) -> Result<(), std::io::Error> {
         map_serializer.serialize_entry(BUNYAN_VERSION, &self.bunyan_version)?;
         map_serializer.serialize_entry(NAME, &self.name)?;
-        map_serializer.serialize_entry(MESSAGE, &message)?;
-        map_serializer.serialize_entry(LEVEL, &to_bunyan_level(level))?;
+        map_serializer.serialize_entry(MESSAGE, record.message())?;
+        map_serializer.serialize_entry(LEVEL, &record.level())?;
         map_serializer.serialize_entry(HOSTNAME, &self.hostname)?;
         map_serializer.serialize_entry(PID, &self.pid)?;
-        if let Ok(time) = &time::OffsetDateTime::now_utc().format(&Rfc3339) {
-            map_serializer.serialize_entry(TIME, time)?;
-        }
+        map_serializer.serialize_entry(TIME, record.time())?;
         Ok(())
     }
 
@@ -193,65 +394,509 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
         Ok(())
     }
 
-    /// Given a span, it serialised it to a in-memory buffer (vector of bytes).
-    fn serialize_span<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+    /// Serialize the call site of a span or an event, according to `self.source_location`.
+    fn serialize_source_location(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        metadata: &Metadata<'_>,
+    ) -> Result<(), std::io::Error> {
+        self.serialize_field(map_serializer, "target", metadata.target())?;
+        match self.source_location {
+            SourceLocation::Flat => {
+                self.serialize_field(map_serializer, "line", &metadata.line())?;
+                self.serialize_field(map_serializer, "file", &metadata.file())?;
+            }
+            SourceLocation::Nested => {
+                let src = SourceObject {
+                    metadata,
+                    skip_fields: &self.skip_fields,
+                };
+                self.serialize_field(map_serializer, SOURCE, &src)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start merging the non-core fields of a record.
+    ///
+    /// Keys generated by the layer are reserved: user-provided fields can't override them.
+    fn merged_fields(&self) -> MergedFields<'_> {
+        MergedFields::new(
+            self.key_collision_policy,
+            self.field_ordering,
+            &self.reserved_fields,
+        )
+    }
+
+    /// Serialize the identifiers of the span a record belongs to, according to `self.span_ids`.
+    fn serialize_span_ids<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        span: &SpanRef<S>,
+    ) -> Result<(), std::io::Error> {
+        if self.span_ids == SpanIds::Disabled {
+            return Ok(());
+        }
+        self.serialize_field(map_serializer, SPAN_ID, &self.span_id(span))?;
+        if let Some(parent) = span.parent() {
+            self.serialize_field(map_serializer, PARENT_SPAN_ID, &self.span_id(&parent))?;
+        }
+        if let Some(root) = span.scope().last() {
+            self.serialize_field(map_serializer, ROOT_SPAN_ID, &self.span_id(&root))?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the spans a record belongs to, from the root to the leaf, according to
+    /// `self.span_path` and `self.span_list`.
+    fn serialize_span_scope<
+        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    >(
         &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
         span: &SpanRef<S>,
-        ty: Type,
-    ) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = Vec::new();
-        let mut serializer = serde_json::Serializer::new(&mut buffer);
-        let mut map_serializer = serializer.serialize_map(None)?;
-        let message = format_span_context(span, ty);
-        self.serialize_bunyan_core_fields(&mut map_serializer, &message, span.metadata().level())?;
-        // Additional metadata useful for debugging
-        // They should be nested under `src` (see https://github.com/trentm/node-bunyan#src )
-        // but `tracing` does not support nested values yet
-        self.serialize_field(&mut map_serializer, "target", span.metadata().target())?;
-        self.serialize_field(&mut map_serializer, "line", &span.metadata().line())?;
-        self.serialize_field(&mut map_serializer, "file", &span.metadata().file())?;
-
-        // Add all default fields
-        for (key, value) in self.default_fields.iter() {
-            // Make sure this key isn't reserved. If it is reserved,
-            // silently ignore
-            if !BUNYAN_REQUIRED_FIELDS.contains(&key.as_str()) {
-                self.serialize_field(&mut map_serializer, key, value)?;
+    ) -> Result<(), std::io::Error> {
+        if self.span_path {
+            let mut path = String::new();
+            for span in span.scope().from_root() {
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(span.metadata().name());
+            }
+            self.serialize_field(map_serializer, SPAN_PATH, &path)?;
+        }
+        if self.span_list {
+            let spans = SpanList { layer: self, span };
+            self.serialize_field(map_serializer, SPANS, &spans)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the OpenTelemetry identifiers of the span a record belongs to, if
+    /// `tracing-opentelemetry` is tracking it.
+    #[cfg(feature = "opentelemetry")]
+    fn serialize_opentelemetry_ids<S>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        span: &SpanRef<S>,
+    ) -> Result<(), std::io::Error>
+    where
+        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        if !self.opentelemetry_ids {
+            return Ok(());
+        }
+        if let Some(ids) = otel::OtelIds::of(span) {
+            self.serialize_field(map_serializer, otel::TRACE_ID, &ids.trace_id)?;
+            let span_id_key = self.opentelemetry_span_id_key();
+            self.serialize_field(map_serializer, span_id_key, &ids.span_id)?;
+            if let Some(trace_flags) = &ids.trace_flags {
+                self.serialize_field(map_serializer, otel::TRACE_FLAGS, trace_flags)?;
             }
         }
+        Ok(())
+    }
+
+    /// `span_id`, unless it's already used by [`SpanIds`].
+    #[cfg(feature = "opentelemetry")]
+    fn opentelemetry_span_id_key(&self) -> &'static str {
+        match self.span_ids {
+            SpanIds::Disabled => otel::SPAN_ID,
+            SpanIds::Registry | SpanIds::Generated => otel::NAMESPACED_SPAN_ID,
+        }
+    }
+
+    fn span_id<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<S>,
+    ) -> u64 {
+        match self.span_ids {
+            SpanIds::Generated => span
+                .extensions()
+                .get::<GeneratedSpanId>()
+                .map(|id| id.0)
+                // The span was created before this layer was registered.
+                .unwrap_or_else(|| span.id().into_u64()),
+            SpanIds::Disabled | SpanIds::Registry => span.id().into_u64(),
+        }
+    }
 
-        let extensions = span.extensions();
-        if let Some(visitor) = extensions.get::<JsonStorage>() {
-            for (key, value) in visitor.values() {
-                // Make sure this key isn't reserved. If it is reserved,
-                // silently ignore
-                if !BUNYAN_REQUIRED_FIELDS.contains(key) {
-                    self.serialize_field(&mut map_serializer, key, value)?;
+    /// The fields of an event, in the order required by `self.field_ordering`.
+    ///
+    /// Unless they have to be sorted, or some of them are repeated or left empty, they are
+    /// serialized straight from the event by [`BunyanFormattingLayer::serialize_merged_fields`],
+    /// in the order they were declared.
+    fn event_fields<'a>(
+        &self,
+        event: &'a Event<'_>,
+        event_visitor: &'a mut Option<JsonStorage<'_>>,
+    ) -> Source<'a> {
+        if self.field_ordering != FieldOrdering::Alphabetical && records_distinct_fields(event) {
+            return Source::declared(FieldSource::Event, event.metadata().fields());
+        }
+        let event_visitor = event_visitor.insert(JsonStorage::default());
+        event.record(event_visitor);
+        let source = Source::storage(FieldSource::Event, event_visitor);
+        match self.field_ordering {
+            FieldOrdering::Declaration => {
+                let mut declared: Vec<(&str, &Value)> = Vec::new();
+                for field in event.metadata().fields() {
+                    if let Some((key, value)) = lookup_field(event_visitor, field.name()) {
+                        if !declared.iter().any(|(k, _)| *k == key) {
+                            declared.push((key, value));
+                        }
+                    }
                 }
+                source.ordered(declared)
             }
+            FieldOrdering::Unordered | FieldOrdering::Alphabetical => source,
         }
-        map_serializer.end()?;
-        // We add a trailing new line.
-        buffer.write_all(b"\n")?;
-        Ok(buffer)
     }
 
-    /// Given an in-memory buffer holding a complete serialised record, flush it to the writer
-    /// returned by self.make_writer.
+    /// The fields stored for a span (including the ones inherited from its parents),
+    /// in the order required by `self.field_ordering`.
+    fn span_fields<'a, S>(&self, span: &SpanRef<S>, storage: &'a JsonStorage<'a>) -> Source<'a>
+    where
+        S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        let source = Source::storage(FieldSource::Span, storage);
+        match self.field_ordering {
+            FieldOrdering::Declaration => {
+                let mut declared: Vec<(&str, &Value)> = Vec::new();
+                for span in span.scope().from_root() {
+                    for field in span.metadata().fields() {
+                        if let Some((key, value)) = lookup_field(storage, field.name()) {
+                            if !declared.iter().any(|(k, _)| *k == key) {
+                                declared.push((key, value));
+                            }
+                        }
+                    }
+                }
+                let mut undeclared: Vec<(&str, &Value)> = storage
+                    .iter()
+                    .filter(|(key, _)| !declared.iter().any(|(k, _)| k == key))
+                    .collect();
+                undeclared.sort_unstable_by_key(|(key, _)| *key);
+                declared.extend(undeclared);
+                source.ordered(declared)
+            }
+            FieldOrdering::Unordered | FieldOrdering::Alphabetical => source,
+        }
+    }
+
+    /// Resolve key collisions and serialize the resulting fields.
+    ///
+    /// The fields of `event` are serialized if a [`Source::declared`] source was pushed for it.
+    fn serialize_merged_fields<M>(
+        &self,
+        map_serializer: &mut M,
+        fields: &MergedFields<'_>,
+        event: Option<&Event<'_>>,
+    ) -> Result<(), std::io::Error>
+    where
+        M: SerializeMap<Error = serde_json::Error>,
+    {
+        fields.try_for_each(|field| match field {
+            // Fields moved to a namespaced key are skipped by their name as well.
+            MergedField::Value { name, .. } if self.skip_fields.contains(name) => Ok(()),
+            MergedField::Value { key, name, value } => {
+                let value = self.redactor.redact(name, value);
+                self.serialize_field(map_serializer, &key, &value)
+            }
+            MergedField::Declared(resolver) => {
+                if let Some(event) = event {
+                    let mut visitor = EventFieldsSerializer::new(
+                        map_serializer,
+                        resolver,
+                        &self.redactor,
+                        &self.skip_fields,
+                    );
+                    event.record(&mut visitor);
+                    visitor.finish()?;
+                }
+                Ok(())
+            }
+        })
+    }
+
+    /// Check if records should be emitted for the given point in the lifecycle of spans.
+    fn emits_span_event(&self, kind: FmtSpan) -> bool {
+        self.span_events.clone() & kind.clone() == kind
+    }
+
+    /// Build a span record and hand it over to the sink.
+    fn emit_span<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<S>,
+        ty: Type,
+    ) {
+        with_buffers(|buffers| {
+            write_span_context(&mut buffers.message, span, ty);
+            if let Err(error) = self.format_time(&mut buffers.time) {
+                return self.report(&SinkError::serialization(error), span.metadata());
+            }
+            let fields = LayerRecord {
+                layer: self,
+                span: Some(span),
+                event: None,
+            };
+            self.emit(&Record {
+                ty,
+                metadata: span.metadata(),
+                span: Some(span.metadata()),
+                level: self.bunyan_level(span.metadata()),
+                message: &buffers.message,
+                time: &buffers.time,
+                fields: &fields,
+            });
+        });
+    }
+
+    /// Hand a finished record over to the sink.
     ///
-    /// If we write directly to the writer returned by self.make_writer in more than one go
-    /// we can end up with broken/incoherent bits and pieces of those records when
-    /// running multi-threaded/concurrent programs.
-    fn emit(&self, buffer: &[u8], meta: &Metadata<'_>) -> Result<(), std::io::Error> {
-        self.make_writer.make_writer_for(meta).write_all(buffer)
+    /// Records which could not be serialized or written are reported to `self.error_handler`.
+    fn emit(&self, record: &Record<'_>) {
+        if let Err(error) = self.sink.write_record(record) {
+            self.report(&error, record.metadata());
+        }
+    }
+
+    fn report(&self, error: &SinkError, meta: &Metadata<'_>) {
+        error_handler::report(
+            &self.error_handler,
+            &self.error_counters,
+            error.kind(),
+            error.error(),
+            error.record(),
+            Some(meta),
+        );
+    }
+}
+
+/// The fields of a record about a span or an event, serialized on demand.
+struct LayerRecord<'a, 's, S, W>
+where
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    W: RecordSink,
+{
+    layer: &'a BunyanFormattingLayer<W>,
+    /// The span the record is about, or the span the event was emitted in.
+    span: Option<&'a SpanRef<'s, S>>,
+    event: Option<&'a Event<'a>>,
+}
+
+impl<S, W> LayerRecord<'_, '_, S, W>
+where
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    W: RecordSink,
+{
+    fn serialize<M>(&self, map_serializer: &mut M, record: &Record<'_>) -> std::io::Result<()>
+    where
+        M: SerializeMap<Error = serde_json::Error>,
+    {
+        let layer = self.layer;
+        layer.serialize_bunyan_core_fields(map_serializer, record)?;
+        // Additional metadata useful for debugging
+        layer.serialize_source_location(map_serializer, record.metadata())?;
+        if let Some(span) = self.span {
+            layer.serialize_span_ids(map_serializer, span)?;
+            #[cfg(feature = "opentelemetry")]
+            layer.serialize_opentelemetry_ids(map_serializer, span)?;
+            layer.serialize_span_scope(map_serializer, span)?;
+        }
+
+        // Add all default fields, all the other fields associated with the event
+        // (except the message and the level we already used) and all the fields from
+        // the span, if we have one.
+        let mut event_visitor = None;
+        let mut fields = layer.merged_fields();
+        match self.event {
+            Some(event) => {
+                fields.push(
+                    Source::list(FieldSource::Default, &layer.default_fields)
+                        .ignoring(&["message"]),
+                );
+                fields.push(
+                    layer
+                        .event_fields(event, &mut event_visitor)
+                        .ignoring(&["message", LEVEL_FIELD]),
+                );
+            }
+            None => fields.push(Source::list(FieldSource::Default, &layer.default_fields)),
+        }
+        // `JsonStorageLayer` might have filtered out the span: fall back to the fields stored
+        // for its closest ancestor.
+        let ancestor;
+        let span = match self.span {
+            Some(span) if span.extensions().get::<JsonStorage>().is_none() => {
+                ancestor = span
+                    .scope()
+                    .skip(1)
+                    .find(|span| span.extensions().get::<JsonStorage>().is_some());
+                ancestor.as_ref()
+            }
+            span => span,
+        };
+        let extensions = span.map(|span| span.extensions());
+        if let (Some(span), Some(visitor)) = (
+            span,
+            extensions
+                .as_ref()
+                .and_then(|extensions| extensions.get::<JsonStorage>()),
+        ) {
+            fields.push(layer.span_fields(span, visitor));
+        }
+        layer.serialize_merged_fields(map_serializer, &fields, self.event)
     }
 }
 
-/// The type of record we are dealing with: entering a span, exiting a span, an event.
-#[derive(Clone, Debug)]
+impl<S, W> RecordFields for LayerRecord<'_, '_, S, W>
+where
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    W: RecordSink,
+{
+    fn write_json(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        let mut serializer = serde_json::Serializer::new(buffer);
+        let mut map_serializer = serializer.serialize_map(None)?;
+        self.serialize(&mut map_serializer, record)?;
+        map_serializer.end()?;
+        Ok(())
+    }
+
+    fn to_json_map(&self, record: &Record<'_>) -> std::io::Result<serde_json::Map<String, Value>> {
+        let mut map_serializer = serde_json::value::Serializer.serialize_map(None)?;
+        self.serialize(&mut map_serializer, record)?;
+        match map_serializer.end()? {
+            Value::Object(map) => Ok(map),
+            _ => unreachable!("A map is always serialized as an object"),
+        }
+    }
+}
+
+/// The `spans` of a record: for each span from the root to the leaf, its name, its identifier
+/// and its own fields.
+struct SpanList<'a, 's, S, W>
+where
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    W: RecordSink,
+{
+    layer: &'a BunyanFormattingLayer<W>,
+    span: &'a SpanRef<'s, S>,
+}
+
+impl<S, W> Serialize for SpanList<'_, '_, S, W>
+where
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    W: RecordSink,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        use serde::ser::SerializeSeq;
+
+        let mut seq = serializer.serialize_seq(None)?;
+        for span in self.span.scope().from_root() {
+            let id = self.layer.span_id(&span);
+            let extensions = span.extensions();
+            seq.serialize_element(&SpanListEntry {
+                layer: self.layer,
+                name: span.metadata().name(),
+                id,
+                fields: extensions.get::<OwnFields>(),
+            })?;
+        }
+        seq.end()
+    }
+}
+
+struct SpanListEntry<'a, W: RecordSink> {
+    layer: &'a BunyanFormattingLayer<W>,
+    name: &'static str,
+    id: u64,
+    /// Missing if the span was created before the layer was registered.
+    fields: Option<&'a OwnFields>,
+}
+
+impl<W: RecordSink> Serialize for SpanListEntry<'_, W> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map_serializer = serializer.serialize_map(Some(3))?;
+        map_serializer.serialize_entry("name", self.name)?;
+        map_serializer.serialize_entry("id", &self.id)?;
+        map_serializer.serialize_entry("fields", &SpanListFields(self))?;
+        map_serializer.end()
+    }
+}
+
+/// The own fields of a span, sorted by key, with the skipped fields and the redaction rules
+/// of the layer applied.
+struct SpanListFields<'a, 'e, W: RecordSink>(&'e SpanListEntry<'a, W>);
+
+impl<W: RecordSink> Serialize for SpanListFields<'_, '_, W> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let SpanListEntry { layer, fields, .. } = self.0;
+        let mut values: Vec<(&str, &Value)> = fields
+            .iter()
+            .flat_map(|fields| fields.0.values())
+            .map(|(key, value)| (*key, value))
+            .filter(|(key, _)| !layer.skip_fields.contains(*key))
+            .collect();
+        values.sort_unstable_by_key(|(key, _)| *key);
+        let mut map_serializer = serializer.serialize_map(Some(values.len()))?;
+        for (key, value) in values {
+            map_serializer.serialize_entry(key, &layer.redactor.redact(key, value))?;
+        }
+        map_serializer.end()
+    }
+}
+
+/// Find the value recorded in `storage` for the field named `name`.
+///
+/// Raw identifiers (e.g. `r#type`) might have been stored without their `r#` prefix.
+fn lookup_field<'a>(storage: &'a JsonStorage<'a>, name: &str) -> Option<(&'a str, &'a Value)> {
+    storage
+        .get_key_value(name)
+        .or_else(|| storage.get_key_value(name.strip_prefix("r#")?))
+}
+
+/// The Bunyan `src` object (see https://github.com/trentm/node-bunyan#src ).
+///
+/// Sub-keys can be skipped individually using their dotted path (e.g. `src.line`).
+struct SourceObject<'a> {
+    metadata: &'a Metadata<'a>,
+    skip_fields: &'a HashSet<String>,
+}
+
+impl Serialize for SourceObject<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map_serializer = serializer.serialize_map(None)?;
+        let entries = [
+            ("src.file", self.metadata.file().map(Value::from)),
+            ("src.line", self.metadata.line().map(Value::from)),
+            ("src.func", self.metadata.module_path().map(Value::from)),
+        ];
+        for (path, value) in entries {
+            if let Some(value) = value {
+                if !self.skip_fields.contains(path) {
+                    map_serializer.serialize_entry(&path[SOURCE.len() + 1..], &value)?;
+                }
+            }
+        }
+        map_serializer.end()
+    }
+}
+
+/// The type of record we are dealing with: a point in the lifecycle of a span or an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Type {
+    /// A span has been created (`[SPAN - START]`).
     EnterSpan,
+    /// A span has been closed (`[SPAN - END]`).
     ExitSpan,
+    /// A span has been entered (`[SPAN - ENTER]`): it can happen many times for the same span,
+    /// e.g. every time an instrumented future is polled.
+    Enter,
+    /// A span has been exited (`[SPAN - EXIT]`).
+    Exit,
+    /// An event has been emitted (`[SPAN - EVENT]`).
     Event,
 }
 
@@ -260,6 +905,8 @@ impl fmt::Display for Type {
         let repr = match self {
             Type::EnterSpan => "START",
             Type::ExitSpan => "END",
+            Type::Enter => "ENTER",
+            Type::Exit => "EXIT",
             Type::Event => "EVENT",
         };
         write!(f, "{}", repr)
@@ -269,11 +916,14 @@ impl fmt::Display for Type {
 /// Ensure consistent formatting of the span context.
 ///
 /// Example: "[AN_INTERESTING_SPAN - START]"
-fn format_span_context<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+fn write_span_context<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+    buffer: &mut String,
     span: &SpanRef<S>,
     ty: Type,
-) -> String {
-    format!("[{} - {}]", span.metadata().name().to_uppercase(), ty)
+) {
+    buffer.push('[');
+    buffer.extend(span.metadata().name().chars().flat_map(char::to_uppercase));
+    let _ = write!(buffer, " - {}]", ty);
 }
 
 /// Ensure consistent formatting of event message.
@@ -281,116 +931,168 @@ fn format_span_context<S: Subscriber + for<'a> tracing_subscriber::registry::Loo
 /// Examples:
 /// - "[AN_INTERESTING_SPAN - EVENT] My event message" (for an event with a parent span)
 /// - "My event message" (for an event without a parent span)
-fn format_event_message<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+///
+/// It returns the level set by the reserved `bunyan.level` field of the event, if any.
+fn write_event_message<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+    buffer: &mut String,
     current_span: &Option<SpanRef<S>>,
     event: &Event,
-    event_visitor: &JsonStorage<'_>,
-) -> String {
-    // Extract the "message" field, if provided. Fallback to the target, if missing.
-    let mut message = event_visitor
-        .values()
-        .get("message")
-        .and_then(|v| match v {
-            Value::String(s) => Some(s.as_str()),
-            _ => None,
-        })
-        .unwrap_or_else(|| event.metadata().target())
-        .to_owned();
-
+) -> Option<BunyanLevel> {
     // If the event is in the context of a span, prepend the span name to the message.
     if let Some(span) = &current_span {
-        message = format!("{} {}", format_span_context(span, Type::Event), message);
+        write_span_context(buffer, span, Type::Event);
+        buffer.push(' ');
     }
 
-    message
+    // Extract the "message" field, if provided. Fallback to the target, if missing.
+    let mut visitor = CoreFieldsVisitor::new(buffer);
+    event.record(&mut visitor);
+    let (found_message, level) = (visitor.found_message(), visitor.level());
+    if !found_message {
+        buffer.push_str(event.metadata().target());
+    }
+    level
 }
 
 impl<S, W> Layer<S> for BunyanFormattingLayer<W>
 where
     S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
-    W: for<'a> MakeWriter<'a> + 'static,
+    W: RecordSink,
 {
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        // Events do not necessarily happen in the context of a span, hence lookup_current
+        // Events do not necessarily happen in the context of a span, hence event_span
         // returns an `Option<SpanRef<_>>` instead of a `SpanRef<_>`.
-        let current_span = ctx.lookup_current();
-
-        let mut event_visitor = JsonStorage::default();
-        event.record(&mut event_visitor);
-
-        // Opting for a closure to use the ? operator and get more linear code.
-        let format = || {
-            let mut buffer = Vec::new();
-
-            let mut serializer = serde_json::Serializer::new(&mut buffer);
-            let mut map_serializer = serializer.serialize_map(None)?;
-
-            let message = format_event_message(&current_span, event, &event_visitor);
-            self.serialize_bunyan_core_fields(
-                &mut map_serializer,
-                &message,
-                event.metadata().level(),
-            )?;
-            // Additional metadata useful for debugging
-            // They should be nested under `src` (see https://github.com/trentm/node-bunyan#src )
-            // but `tracing` does not support nested values yet
-            self.serialize_field(&mut map_serializer, "target", event.metadata().target())?;
-            self.serialize_field(&mut map_serializer, "line", &event.metadata().line())?;
-            self.serialize_field(&mut map_serializer, "file", &event.metadata().file())?;
-
-            // Add all default fields
-            for (key, value) in self.default_fields.iter().filter(|(key, _)| {
-                key.as_str() != "message" && !BUNYAN_REQUIRED_FIELDS.contains(&key.as_str())
-            }) {
-                self.serialize_field(&mut map_serializer, key, value)?;
-            }
+        // It honours explicit parents (`parent: &span`) and explicit roots (`parent: None`),
+        // falling back to the current span for contextual events.
+        let current_span = ctx.event_span(event);
 
-            // Add all the other fields associated with the event, expect the message we already used.
-            for (key, value) in event_visitor
-                .values()
-                .iter()
-                .filter(|(&key, _)| key != "message" && !BUNYAN_REQUIRED_FIELDS.contains(&key))
-            {
-                self.serialize_field(&mut map_serializer, key, value)?;
+        with_buffers(|buffers| {
+            let level = write_event_message(&mut buffers.message, &current_span, event)
+                .unwrap_or_else(|| self.bunyan_level(event.metadata()));
+            if let Err(error) = self.format_time(&mut buffers.time) {
+                return self.report(&SinkError::serialization(error), event.metadata());
             }
+            let fields = LayerRecord {
+                layer: self,
+                span: current_span.as_ref(),
+                event: Some(event),
+            };
+            self.emit(&Record {
+                ty: Type::Event,
+                metadata: event.metadata(),
+                span: current_span.as_ref().map(|span| span.metadata()),
+                level,
+                message: &buffers.message,
+                time: &buffers.time,
+                fields: &fields,
+            });
+        });
+    }
 
-            // Add all the fields from the current span, if we have one.
-            if let Some(span) = &current_span {
-                let extensions = span.extensions();
-                if let Some(visitor) = extensions.get::<JsonStorage>() {
-                    for (key, value) in visitor.values() {
-                        // Make sure this key isn't reserved. If it is reserved,
-                        // silently ignore
-                        if !BUNYAN_REQUIRED_FIELDS.contains(key) {
-                            self.serialize_field(&mut map_serializer, key, value)?;
-                        }
-                    }
-                }
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if self.json_storage {
+            storage_layer::store_new_span(attrs, &span);
+        }
+        if self.span_ids == SpanIds::Generated {
+            let mut extensions = span.extensions_mut();
+            // Another layer stacked on the same registry might have generated it already:
+            // records written by both layers must carry the same identifier.
+            if extensions.get_mut::<GeneratedSpanId>().is_none() {
+                extensions.insert(GeneratedSpanId::next());
             }
-            map_serializer.end()?;
-            // We add a trailing new line.
-            buffer.write_all(b"\n")?;
+        }
+        // Another layer stacked on the same registry might have stored them already.
+        if self.span_list && span.extensions().get::<OwnFields>().is_none() {
+            let mut fields = JsonStorage::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(OwnFields(fields));
+        }
+        #[cfg(feature = "opentelemetry")]
+        if self.opentelemetry_ids {
+            otel::OtelIds::refresh(&span);
+        }
+        if !self.emits_span_event(FmtSpan::NEW) {
+            return;
+        }
+        self.emit_span(&span, Type::EnterSpan);
+    }
 
-            Ok(buffer)
+    fn on_record(&self, id: &Id, values: &tracing::span::Record<'_>, ctx: Context<'_, S>) {
+        if !self.json_storage && !self.span_list {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
         };
+        if self.json_storage {
+            storage_layer::store_record(values, &span);
+        }
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<OwnFields>() {
+            values.record(&mut fields.0);
+        }
+    }
 
-        let result: std::io::Result<Vec<u8>> = format();
-        if let Ok(formatted) = result {
-            let _ = self.emit(&formatted, event.metadata());
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let emits_span_event = self.emits_span_event(FmtSpan::ENTER);
+        if !self.json_storage && !emits_span_event {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if self.json_storage {
+            storage_layer::store_enter(&span);
+        }
+        if emits_span_event {
+            self.emit_span(&span, Type::Enter);
         }
     }
 
-    fn on_new_span(&self, _attrs: &Attributes, id: &Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
-        if let Ok(serialized) = self.serialize_span(&span, Type::EnterSpan) {
-            let _ = self.emit(&serialized, span.metadata());
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        #[cfg(feature = "opentelemetry")]
+        if self.opentelemetry_ids {
+            // The sampling decision for the span might have been made in the meantime.
+            if let Some(span) = ctx.span(id) {
+                otel::OtelIds::refresh(&span);
+            }
+        }
+        if !self.emits_span_event(FmtSpan::EXIT) {
+            return;
         }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        self.emit_span(&span, Type::Exit);
     }
 
     fn on_close(&self, id: Id, ctx: Context<'_, S>) {
-        let span = ctx.span(&id).expect("Span not found, this is a bug");
-        if let Ok(serialized) = self.serialize_span(&span, Type::ExitSpan) {
-            let _ = self.emit(&serialized, span.metadata());
+        let emits_span_event = self.emits_span_event(FmtSpan::CLOSE);
+        if !self.json_storage && !emits_span_event {
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        if self.json_storage {
+            storage_layer::store_close(&span);
+        }
+        if emits_span_event {
+            self.emit_span(&span, Type::ExitSpan);
+        }
+    }
+
+    /// Expose the extensions of spans to [`SpanExt`](crate::SpanExt) when storing span fields.
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if self.json_storage {
+            storage_layer::downcast_with_extensions::<S>(id)
+        } else {
+            None
         }
     }
 }
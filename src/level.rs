@@ -0,0 +1,86 @@
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use tracing_core::metadata::Level;
+use tracing_log::AsLog;
+
+/// The reserved event field overriding the level of a record, e.g. `bunyan.level = "fatal"`.
+pub(crate) const LEVEL_FIELD: &str = "bunyan.level";
+
+/// The numeric level of a Bunyan record (see https://github.com/trentm/node-bunyan#levels ).
+///
+/// `tracing` levels are mapped to Bunyan's `trace` to `error` levels, while `fatal` can be
+/// emitted using the reserved `bunyan.level` field or a custom mapping, see
+/// [`BunyanFormattingLayerBuilder::level_mapping`](crate::BunyanFormattingLayerBuilder::level_mapping).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BunyanLevel(u16);
+
+impl BunyanLevel {
+    /// `trace` (10).
+    pub const TRACE: BunyanLevel = BunyanLevel(10);
+    /// `debug` (20).
+    pub const DEBUG: BunyanLevel = BunyanLevel(20);
+    /// `info` (30).
+    pub const INFO: BunyanLevel = BunyanLevel(30);
+    /// `warn` (40).
+    pub const WARN: BunyanLevel = BunyanLevel(40);
+    /// `error` (50).
+    pub const ERROR: BunyanLevel = BunyanLevel(50);
+    /// `fatal` (60), which has no `tracing` equivalent.
+    pub const FATAL: BunyanLevel = BunyanLevel(60);
+
+    /// A level that doesn't match any of Bunyan's named levels.
+    pub const fn custom(level: u16) -> Self {
+        BunyanLevel(level)
+    }
+
+    /// The numeric value of the level, as emitted in records.
+    pub const fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    /// Parse the name of a level, ignoring ASCII case, e.g. `"fatal"`.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        [
+            ("trace", Self::TRACE),
+            ("debug", Self::DEBUG),
+            ("info", Self::INFO),
+            ("warn", Self::WARN),
+            ("error", Self::ERROR),
+            ("fatal", Self::FATAL),
+        ]
+        .iter()
+        .find(|(level_name, _)| level_name.eq_ignore_ascii_case(name))
+        .map(|(_, level)| *level)
+    }
+}
+
+/// Convert from log levels to Bunyan's levels.
+impl From<&Level> for BunyanLevel {
+    fn from(level: &Level) -> Self {
+        match level.as_log() {
+            log::Level::Error => Self::ERROR,
+            log::Level::Warn => Self::WARN,
+            log::Level::Info => Self::INFO,
+            log::Level::Debug => Self::DEBUG,
+            log::Level::Trace => Self::TRACE,
+        }
+    }
+}
+
+impl From<Level> for BunyanLevel {
+    fn from(level: Level) -> Self {
+        Self::from(&level)
+    }
+}
+
+impl fmt::Display for BunyanLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for BunyanLevel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.0)
+    }
+}
@@ -1,8 +1,36 @@
 #![allow(clippy::needless_doctest_main)]
 #![doc = include_str!("../README.md")]
 
+mod buffers;
+mod builder;
+mod error_handler;
+mod event_visitor;
+mod fields;
 mod formatting_layer;
+mod level;
+mod non_blocking;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod panic_hook;
+mod redaction;
+mod ring_buffer;
+mod sink;
+mod span_ext;
 mod storage_layer;
+mod streams;
+mod timestamp;
 
+pub use builder::*;
+pub use error_handler::*;
+pub use fields::*;
 pub use formatting_layer::*;
+pub use level::*;
+pub use non_blocking::*;
+pub use panic_hook::*;
+pub use redaction::*;
+pub use ring_buffer::*;
+pub use sink::*;
+pub use span_ext::*;
 pub use storage_layer::*;
+pub use streams::*;
+pub use timestamp::*;
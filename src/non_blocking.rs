@@ -0,0 +1,389 @@
+use crate::error_handler::{self, ErrorCounters, ErrorHandler, FormattingErrorKind};
+use serde::de::IgnoredAny;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{JoinHandle, ThreadId};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The key used to report the number of records dropped since the previous record,
+/// see [`OverflowPolicy`].
+const DROPPED_RECORDS: &str = "dropped_records";
+
+/// The key used instead of [`DROPPED_RECORDS`] if a record already has a field with it.
+const NAMESPACED_DROPPED_RECORDS: &str = "non_blocking.dropped_records";
+
+/// What [`NonBlocking`] does with a new record when its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the background thread to make room in the queue: no record is lost, but
+    /// a slow writer slows down the threads emitting records.
+    ///
+    /// This is the default.
+    #[default]
+    Block,
+    /// Drop the new record.
+    DropNewest,
+    /// Drop the oldest record in the queue, to make room for the new one.
+    DropOldest,
+}
+
+/// A builder for [`NonBlocking`].
+///
+/// ```rust
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, NonBlockingBuilder, OverflowPolicy};
+///
+/// let (non_blocking, _guard) = NonBlockingBuilder::default()
+///     .buffered_records(1024)
+///     .overflow_policy(OverflowPolicy::DropOldest)
+///     .finish(std::io::stdout());
+/// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), non_blocking);
+/// ```
+#[derive(Debug)]
+pub struct NonBlockingBuilder {
+    buffered_records: usize,
+    overflow_policy: OverflowPolicy,
+    thread_name: String,
+    error_handler: ErrorHandler,
+}
+
+impl Default for NonBlockingBuilder {
+    fn default() -> Self {
+        Self {
+            buffered_records: 8192,
+            overflow_policy: OverflowPolicy::default(),
+            thread_name: "tracing-bunyan-formatter".to_owned(),
+            error_handler: ErrorHandler::default(),
+        }
+    }
+}
+
+impl NonBlockingBuilder {
+    /// Set the maximum number of records waiting to be written. It defaults to 8192.
+    pub fn buffered_records(mut self, buffered_records: usize) -> Self {
+        self.buffered_records = buffered_records.max(1);
+        self
+    }
+
+    /// Choose what happens to new records when the queue is full.
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Set the name of the background thread.
+    pub fn thread_name(mut self, thread_name: impl Into<String>) -> Self {
+        self.thread_name = thread_name.into();
+        self
+    }
+
+    /// Choose what happens to the records the background thread fails to write.
+    ///
+    /// Records are written after [`BunyanFormattingLayer`](crate::BunyanFormattingLayer)
+    /// handed them over, so their failures are reported to this handler rather than to the
+    /// one of the layer, without [`FormattingError::metadata`](crate::FormattingError::metadata).
+    /// The handler runs on the background thread: avoid emitting spans or events from it.
+    /// Failures are counted in any case, see [`NonBlocking::error_counters`].
+    pub fn error_handler(mut self, error_handler: ErrorHandler) -> Self {
+        self.error_handler = error_handler;
+        self
+    }
+
+    /// Spawn the background thread writing records to `writer`.
+    ///
+    /// Keep the returned [`WorkerGuard`] alive as long as records are emitted: dropping it
+    /// writes all pending records and stops the background thread.
+    ///
+    /// # Panics
+    ///
+    /// If the background thread can't be spawned.
+    pub fn finish<W: Write + Send + 'static>(self, writer: W) -> (NonBlocking, WorkerGuard) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            capacity: self.buffered_records,
+            overflow_policy: self.overflow_policy,
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            flushed: Condvar::new(),
+            dropped_records: AtomicU64::new(0),
+            error_handler: self.error_handler,
+            error_counters: ErrorCounters::default(),
+        });
+        let worker = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name(self.thread_name)
+                .spawn(move || shared.work(writer))
+                .expect("Failed to spawn the thread of the non-blocking writer")
+        };
+        shared.lock().worker = Some(worker.thread().id());
+        let guard = WorkerGuard {
+            shared: shared.clone(),
+            worker: Some(worker),
+        };
+        (NonBlocking { shared }, guard)
+    }
+}
+
+/// Spawn a background thread writing records to `writer`, using the default configuration
+/// of [`NonBlockingBuilder`].
+///
+/// ```rust
+/// use tracing_bunyan_formatter::{non_blocking, BunyanFormattingLayer};
+///
+/// let (non_blocking, _guard) = non_blocking(std::io::stdout());
+/// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), non_blocking);
+/// ```
+pub fn non_blocking<W: Write + Send + 'static>(writer: W) -> (NonBlocking, WorkerGuard) {
+    NonBlockingBuilder::default().finish(writer)
+}
+
+/// A writer handing records over to a background thread through a bounded queue,
+/// so that a slow destination (e.g. a full pipe) doesn't stall the threads emitting them.
+///
+/// Each call to `write` is treated as a complete record, which is how
+/// [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) writes them.
+/// When records are dropped, according to the [`OverflowPolicy`], the next record written
+/// gets a `dropped_records` field with the number of records lost since the previous one
+/// (`non_blocking.dropped_records` if the record already has a `dropped_records` field).
+///
+/// Failures of the background thread are reported to its own [`ErrorHandler`], see
+/// [`NonBlockingBuilder::error_handler`].
+#[derive(Clone, Debug)]
+pub struct NonBlocking {
+    shared: Arc<Shared>,
+}
+
+impl NonBlocking {
+    /// The total number of records dropped so far.
+    pub fn dropped_records(&self) -> u64 {
+        self.shared.dropped_records.load(Ordering::Relaxed)
+    }
+
+    /// A handle to the number of writes and flushes the background thread failed.
+    ///
+    /// See [`NonBlockingBuilder::error_handler`] to handle these records.
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.shared.error_counters.clone()
+    }
+}
+
+/// `flush` waits for the background thread to write (and flush) all the records handed
+/// over so far, e.g. before the process exits.
+impl Write for &NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.flush();
+        Ok(())
+    }
+}
+
+impl Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = &'a NonBlocking;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+/// Writes all pending records and stops the background thread of a [`NonBlocking`] writer
+/// when dropped.
+///
+/// Records emitted afterwards are dropped.
+#[must_use = "Dropping the guard stops the background thread of the writer"]
+#[derive(Debug)]
+pub struct WorkerGuard {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.not_empty.notify_one();
+        // Wake up the threads blocked on a full queue: their records are dropped.
+        self.shared.not_full.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Vec<u8>>,
+    /// Records dropped since the last record handed to the background thread.
+    dropped: u64,
+    shutdown: bool,
+    /// The number of records queued so far.
+    queued: u64,
+    /// The number of records written (and flushed, if requested) or evicted so far.
+    written: u64,
+    /// Whether the writer should be flushed after writing the current batch.
+    flush_requested: bool,
+    worker: Option<ThreadId>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    not_empty: Condvar,
+    not_full: Condvar,
+    flushed: Condvar,
+    dropped_records: AtomicU64,
+    error_handler: ErrorHandler,
+    error_counters: ErrorCounters,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The lock is never held while writing records: the state is consistent even if
+        // another thread panicked while holding it.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, record: Vec<u8>) {
+        let mut state = self.lock();
+        while !state.shutdown && state.queue.len() >= self.capacity {
+            match self.overflow_policy {
+                OverflowPolicy::Block => {
+                    state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                OverflowPolicy::DropNewest => return self.drop_record(&mut state),
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    // Evicted records won't be written: they don't hold flushes back.
+                    state.written += 1;
+                    self.drop_record(&mut state);
+                }
+            }
+        }
+        if state.shutdown {
+            return self.drop_record(&mut state);
+        }
+        state.queue.push_back(record);
+        state.queued += 1;
+        drop(state);
+        self.not_empty.notify_one();
+    }
+
+    /// Wait for the records queued so far to be written, then flushed.
+    fn flush(&self) {
+        let mut state = self.lock();
+        // The background thread can't wait for itself, e.g. if a panic hook flushes
+        // the writer from it.
+        if state.worker == Some(std::thread::current().id()) {
+            return;
+        }
+        let queued = state.queued;
+        state.flush_requested = true;
+        self.not_empty.notify_one();
+        while !state.shutdown && state.written < queued {
+            state = self.flushed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn drop_record(&self, state: &mut State) {
+        state.dropped += 1;
+        self.dropped_records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a record (or a flush) the background thread failed to write and hand it over
+    /// to the [`ErrorHandler`].
+    fn report(&self, error: &io::Error, record: Option<&[u8]>) {
+        error_handler::report(
+            &self.error_handler,
+            &self.error_counters,
+            FormattingErrorKind::Io,
+            error,
+            record,
+            None,
+        );
+    }
+
+    /// The loop of the background thread: write records in batches, until shutdown.
+    fn work<W: Write>(&self, mut writer: W) {
+        let mut batch = Vec::new();
+        loop {
+            let (dropped, shutdown, flush) = {
+                let mut state = self.lock();
+                while state.queue.is_empty() && !state.shutdown && !state.flush_requested {
+                    state = self
+                        .not_empty
+                        .wait(state)
+                        .unwrap_or_else(|e| e.into_inner());
+                }
+                batch.extend(state.queue.drain(..));
+                (
+                    std::mem::take(&mut state.dropped),
+                    state.shutdown,
+                    std::mem::take(&mut state.flush_requested),
+                )
+            };
+            self.not_full.notify_all();
+
+            let written = batch.len() as u64;
+            for (i, record) in batch.drain(..).enumerate() {
+                let record = if i == 0 && dropped > 0 {
+                    with_dropped_records(record, dropped)
+                } else {
+                    record
+                };
+                if let Err(error) = writer.write_all(&record) {
+                    self.report(&error, Some(&record));
+                }
+            }
+            if flush || shutdown {
+                if let Err(error) = writer.flush() {
+                    self.report(&error, None);
+                }
+            }
+            self.lock().written += written;
+            self.flushed.notify_all();
+            if shutdown {
+                return;
+            }
+        }
+    }
+}
+
+/// Add the number of dropped records to a serialized record, if it is a JSON object.
+///
+/// If the record already has a field with the same key, the number is added under a
+/// namespaced key instead, unless it is taken as well.
+fn with_dropped_records(mut record: Vec<u8>, dropped: u64) -> Vec<u8> {
+    let keys = match serde_json::from_slice::<HashMap<String, IgnoredAny>>(&record) {
+        Ok(keys) if !keys.is_empty() => keys,
+        _ => return record,
+    };
+    let key = [DROPPED_RECORDS, NAMESPACED_DROPPED_RECORDS]
+        .iter()
+        .copied()
+        .find(|key| !keys.contains_key(*key));
+    // The record is a JSON object: its last `}` closes it.
+    match (key, record.iter().rposition(|byte| *byte == b'}')) {
+        (Some(key), Some(end)) => {
+            let field = format!(",\"{}\":{}", key, dropped);
+            record.splice(end..end, field.into_bytes());
+            record
+        }
+        _ => record,
+    }
+}
@@ -0,0 +1,82 @@
+use opentelemetry::trace::{SamplingDecision, TraceContextExt, TraceFlags, TraceId};
+use opentelemetry::SpanId;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// Keys of the OpenTelemetry identifiers of the span a record belongs to.
+pub(crate) const TRACE_ID: &str = "trace_id";
+pub(crate) const SPAN_ID: &str = "span_id";
+/// The key of the OpenTelemetry span identifier when `span_id` is used by
+/// [`SpanIds`](crate::SpanIds).
+pub(crate) const NAMESPACED_SPAN_ID: &str = "otel.span_id";
+pub(crate) const TRACE_FLAGS: &str = "trace_flags";
+
+/// The OpenTelemetry context of a span, as stored by `tracing-opentelemetry` in its extensions.
+///
+/// `tracing-opentelemetry` drops its data when a span is closed, before `[SPAN - END]` records
+/// are emitted: a copy of the identifiers is kept in the extensions of the span as well.
+#[derive(Clone)]
+pub(crate) struct OtelIds {
+    pub(crate) trace_id: String,
+    pub(crate) span_id: String,
+    /// `None` until the sampling decision for the span has been made, unless it can be
+    /// inherited from the parent context.
+    pub(crate) trace_flags: Option<String>,
+}
+
+impl OtelIds {
+    /// Read the OpenTelemetry context of `span`, if `tracing-opentelemetry` is tracking it,
+    /// falling back to the last copy kept by [`OtelIds::refresh`].
+    pub(crate) fn of<S>(span: &SpanRef<S>) -> Option<Self>
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let extensions = span.extensions();
+        match extensions.get::<OtelData>() {
+            Some(data) => Self::from_data(data),
+            None => extensions.get::<OtelIds>().cloned(),
+        }
+    }
+
+    /// Keep a copy of the current OpenTelemetry context of `span` in its extensions.
+    pub(crate) fn refresh<S>(span: &SpanRef<S>)
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let ids = span
+            .extensions()
+            .get::<OtelData>()
+            .and_then(Self::from_data);
+        if let Some(ids) = ids {
+            span.extensions_mut().replace(ids);
+        }
+    }
+
+    fn from_data(data: &OtelData) -> Option<Self> {
+        let parent = data.parent_cx.span();
+        let parent = parent.span_context();
+        let trace_id = data
+            .builder
+            .trace_id
+            .filter(|trace_id| *trace_id != TraceId::INVALID)
+            .or_else(|| Some(parent.trace_id()).filter(|_| parent.is_valid()))?;
+        let span_id = data
+            .builder
+            .span_id
+            .filter(|span_id| *span_id != SpanId::INVALID)?;
+        let trace_flags = match &data.builder.sampling_result {
+            Some(result) => Some(
+                parent
+                    .trace_flags()
+                    .with_sampled(result.decision == SamplingDecision::RecordAndSample),
+            ),
+            None if parent.is_valid() => Some(parent.trace_flags()),
+            None => None,
+        };
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            trace_flags: trace_flags.map(|flags: TraceFlags| format!("{:02x}", flags)),
+        })
+    }
+}
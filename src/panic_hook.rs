@@ -0,0 +1,149 @@
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::Cell;
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The target of the records emitted for panics.
+const PANIC_TARGET: &str = "panic";
+
+thread_local! {
+    /// Set while a panic is being reported on the current thread, to avoid reporting
+    /// the panics of the subscriber itself over and over again.
+    static REPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears [`REPORTING`] when dropped, even if reporting the panic panicked.
+struct ReportingGuard;
+
+impl Drop for ReportingGuard {
+    fn drop(&mut self) {
+        REPORTING.with(|reporting| reporting.set(false));
+    }
+}
+
+type Flush = Box<dyn Fn() + Send + Sync>;
+
+/// A panic hook emitting a `fatal` record for each panic.
+///
+/// The record is emitted as an event on the panicking thread, so it goes through the
+/// subscriber of that thread, e.g. a [`BunyanFormattingLayer`](crate::BunyanFormattingLayer)
+/// with its name, hostname, pid and default fields, and it carries the fields of the span
+/// that was active when the thread panicked. On top of the panic message (`msg`), it has:
+///
+/// - `panic.file`, `panic.line` and `panic.column`, the location of the panic;
+/// - `thread.name`, the name of the panicking thread, if any;
+/// - `backtrace`, if one was captured (see [`PanicHook::force_backtrace`]).
+///
+/// The `file` and `line` core fields point to the hook itself.
+///
+/// ```rust
+/// use tracing_bunyan_formatter::{non_blocking, BunyanFormattingLayer, PanicHook};
+///
+/// let (non_blocking, _guard) = non_blocking(std::io::stdout());
+/// let formatting_layer =
+///     BunyanFormattingLayer::new("tracing_example".into(), non_blocking.clone());
+/// // Write the record of the panic before the process exits, even if `panic = "abort"`.
+/// PanicHook::default().flush(non_blocking).install();
+/// ```
+pub struct PanicHook {
+    flush: Vec<Flush>,
+    force_backtrace: bool,
+    call_previous_hook: bool,
+}
+
+impl Default for PanicHook {
+    fn default() -> Self {
+        Self {
+            flush: Vec::new(),
+            force_backtrace: false,
+            call_previous_hook: true,
+        }
+    }
+}
+
+impl PanicHook {
+    /// Flush `writer` after emitting the record of a panic, e.g. a
+    /// [`NonBlocking`](crate::NonBlocking) writer, to make sure the record is written
+    /// before the process exits.
+    ///
+    /// It can be called more than once, to flush several writers.
+    pub fn flush<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        let writer = Mutex::new(writer);
+        self.flush.push(Box::new(move || {
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = writer.flush();
+        }));
+        self
+    }
+
+    /// Always capture a backtrace, instead of relying on the `RUST_BACKTRACE` and
+    /// `RUST_LIB_BACKTRACE` environment variables. It defaults to `false`.
+    pub fn force_backtrace(mut self, force_backtrace: bool) -> Self {
+        self.force_backtrace = force_backtrace;
+        self
+    }
+
+    /// Run the panic hook that was installed before this one, after emitting the record,
+    /// e.g. the default hook printing the panic to stderr. It defaults to `true`.
+    pub fn call_previous_hook(mut self, call_previous_hook: bool) -> Self {
+        self.call_previous_hook = call_previous_hook;
+        self
+    }
+
+    /// Install the hook, replacing the current one.
+    pub fn install(self) {
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !REPORTING.with(|reporting| reporting.replace(true)) {
+                let _reporting = ReportingGuard;
+                let payload = info.payload();
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("Box<dyn Any>");
+                let location = info.location();
+                let backtrace = if self.force_backtrace {
+                    Backtrace::force_capture()
+                } else {
+                    Backtrace::capture()
+                };
+                let backtrace = match backtrace.status() {
+                    BacktraceStatus::Captured => Some(backtrace),
+                    _ => None,
+                };
+                let thread = std::thread::current();
+
+                tracing::event!(
+                    target: PANIC_TARGET,
+                    tracing::Level::ERROR,
+                    bunyan.level = "fatal",
+                    panic.file = location.map(|location| location.file()),
+                    panic.line = location.map(|location| location.line()),
+                    panic.column = location.map(|location| location.column()),
+                    thread.name = thread.name(),
+                    backtrace = backtrace.as_ref().map(tracing::field::display),
+                    "{}",
+                    message
+                );
+                for flush in &self.flush {
+                    flush();
+                }
+            }
+            if self.call_previous_hook {
+                previous_hook(info);
+            }
+        }));
+    }
+}
+
+impl fmt::Debug for PanicHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PanicHook")
+            .field("flush", &self.flush.len())
+            .field("force_backtrace", &self.force_backtrace)
+            .field("call_previous_hook", &self.call_previous_hook)
+            .finish()
+    }
+}
@@ -0,0 +1,254 @@
+use crate::builder::BuildError;
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// How the value of a field matched by a [`RedactionRule`] is replaced.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Redaction {
+    /// Replace the value with `"[REDACTED]"`.
+    Mask,
+    /// Replace the value with an arbitrary JSON value.
+    Replace(Value),
+    /// Mask all characters but the last `n` ones, e.g. `"************4242"`.
+    ///
+    /// Numbers and booleans are treated as strings, while arrays and objects are fully masked.
+    /// Values with `n` characters or less are fully masked.
+    RevealLast(usize),
+    /// Replace the value with a marker exposing its length only, e.g. `"[REDACTED: 16 chars]"`.
+    ///
+    /// The length of numbers, booleans, arrays and objects is the length of their JSON representation.
+    Length,
+}
+
+const MASK: &str = "[REDACTED]";
+
+impl Redaction {
+    fn apply(&self, value: &Value) -> Value {
+        match self {
+            Redaction::Mask => Value::from(MASK),
+            Redaction::Replace(replacement) => replacement.clone(),
+            Redaction::RevealLast(n) => match scalar_to_string(value) {
+                Some(s) => {
+                    let len = s.chars().count();
+                    let revealed = if len > *n { *n } else { 0 };
+                    let mut masked = "*".repeat(len - revealed);
+                    masked.extend(s.chars().skip(len - revealed));
+                    Value::from(masked)
+                }
+                None => Value::from(MASK),
+            },
+            Redaction::Length => {
+                let len = match value {
+                    Value::String(s) => s.chars().count(),
+                    value => value.to_string().chars().count(),
+                };
+                Value::from(format!("[REDACTED: {} chars]", len))
+            }
+        }
+    }
+}
+
+fn scalar_to_string(value: &Value) -> Option<Cow<'_, str>> {
+    match value {
+        Value::String(s) => Some(Cow::Borrowed(s)),
+        Value::Number(n) => Some(Cow::Owned(n.to_string())),
+        Value::Bool(b) => Some(Cow::Owned(b.to_string())),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+enum KeyPattern {
+    Exact(String),
+    Glob(String),
+    #[cfg(feature = "regex")]
+    Regex(String),
+}
+
+/// A rule to redact the value of fields, based on their key.
+///
+/// Rules are applied to default fields, event fields and span fields, including keys nested
+/// inside objects (e.g. the ones produced by `valuable`).
+/// A rule matches a key if its pattern matches either the key itself (e.g. `password`) or its
+/// dotted path in the record (e.g. `user.password`).
+///
+/// ```rust
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, Redaction, RedactionRule};
+///
+/// let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+///     .redact(RedactionRule::exact("password"))
+///     .redact(RedactionRule::glob("*token*").with(Redaction::Length))
+///     .redact(RedactionRule::exact("card_number").with(Redaction::RevealLast(4)))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct RedactionRule {
+    pattern: KeyPattern,
+    redaction: Redaction,
+}
+
+impl RedactionRule {
+    /// Match keys equal to `key`, ignoring ASCII case.
+    pub fn exact(key: impl Into<String>) -> Self {
+        Self::new(KeyPattern::Exact(key.into()))
+    }
+
+    /// Match keys using a glob pattern, ignoring ASCII case: `*` matches any sequence of
+    /// characters, `?` matches any single character.
+    pub fn glob(pattern: impl Into<String>) -> Self {
+        Self::new(KeyPattern::Glob(pattern.into()))
+    }
+
+    /// Match keys using a regular expression.
+    ///
+    /// The expression is compiled when building the layer, which fails with
+    /// [`BuildError::InvalidRedactionPattern`] if it is not valid.
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: impl Into<String>) -> Self {
+        Self::new(KeyPattern::Regex(pattern.into()))
+    }
+
+    fn new(pattern: KeyPattern) -> Self {
+        Self {
+            pattern,
+            redaction: Redaction::Mask,
+        }
+    }
+
+    /// Choose how matched values are replaced. It defaults to [`Redaction::Mask`].
+    pub fn with(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+}
+
+enum Matcher {
+    Exact(String),
+    Glob(Vec<char>),
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl Matcher {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Matcher::Exact(expected) => expected.eq_ignore_ascii_case(key),
+            Matcher::Glob(pattern) => {
+                let key: Vec<char> = key.chars().collect();
+                glob_matches(pattern, &key)
+            }
+            #[cfg(feature = "regex")]
+            Matcher::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+/// Iterative glob matching with backtracking on the last `*`, ignoring ASCII case.
+fn glob_matches(pattern: &[char], key: &[char]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(c) if *c == '?' || c.eq_ignore_ascii_case(&key[k]) => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    k = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The compiled set of [`RedactionRule`]s of a layer.
+#[derive(Default)]
+pub(crate) struct Redactor {
+    rules: Vec<(Matcher, Redaction)>,
+}
+
+impl Redactor {
+    pub(crate) fn new(rules: Vec<RedactionRule>) -> Result<Self, BuildError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let matcher = match rule.pattern {
+                    KeyPattern::Exact(key) => Matcher::Exact(key),
+                    KeyPattern::Glob(pattern) => Matcher::Glob(pattern.chars().collect()),
+                    #[cfg(feature = "regex")]
+                    KeyPattern::Regex(pattern) => Matcher::Regex(
+                        regex::Regex::new(&pattern)
+                            .map_err(|_| BuildError::InvalidRedactionPattern(pattern))?,
+                    ),
+                };
+                Ok((matcher, rule.redaction))
+            })
+            .collect::<Result<_, BuildError>>()?;
+        Ok(Self { rules })
+    }
+
+    fn rule_for(&self, key: &str, path: &str) -> Option<&Redaction> {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(key) || (path != key && matcher.matches(path)))
+            .map(|(_, redaction)| redaction)
+    }
+
+    /// Check if there are no rules, i.e. values never have to be redacted.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Redact the value of the field named `name`, including the values nested inside it.
+    pub(crate) fn redact<'v>(&self, name: &str, value: &'v Value) -> Cow<'v, Value> {
+        if self.rules.is_empty() {
+            return Cow::Borrowed(value);
+        }
+        match self.redact_nested(name, name, value) {
+            Some(redacted) => Cow::Owned(redacted),
+            None => Cow::Borrowed(value),
+        }
+    }
+
+    /// Returns `None` if nothing had to be redacted, to avoid cloning the value.
+    fn redact_nested(&self, key: &str, path: &str, value: &Value) -> Option<Value> {
+        if let Some(redaction) = self.rule_for(key, path) {
+            return Some(redaction.apply(value));
+        }
+        match value {
+            Value::Object(map) => {
+                let mut redacted: Option<serde_json::Map<String, Value>> = None;
+                for (nested_key, nested_value) in map {
+                    let nested_path = format!("{}.{}", path, nested_key);
+                    if let Some(v) = self.redact_nested(nested_key, &nested_path, nested_value) {
+                        redacted
+                            .get_or_insert_with(|| map.clone())
+                            .insert(nested_key.clone(), v);
+                    }
+                }
+                redacted.map(Value::Object)
+            }
+            Value::Array(values) => {
+                let mut redacted: Option<Vec<Value>> = None;
+                for (i, nested_value) in values.iter().enumerate() {
+                    if let Some(v) = self.redact_nested(key, path, nested_value) {
+                        redacted.get_or_insert_with(|| values.clone())[i] = v;
+                    }
+                }
+                redacted.map(Value::Array)
+            }
+            _ => None,
+        }
+    }
+}
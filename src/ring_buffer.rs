@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A builder for [`RingBuffer`].
+///
+/// ```rust
+/// use tracing::Level;
+/// use tracing_bunyan_formatter::RingBufferBuilder;
+///
+/// // Keep the last 1000 records, up to 4 MiB, and dump them to stderr on errors.
+/// let ring_buffer = RingBufferBuilder::default()
+///     .max_records(1000)
+///     .max_bytes(4 * 1024 * 1024)
+///     .dump_on(Level::ERROR, std::io::stderr)
+///     .build();
+/// ```
+pub struct RingBufferBuilder {
+    max_records: usize,
+    max_bytes: usize,
+    dump_on: Option<(Level, BoxMakeWriter)>,
+}
+
+impl Default for RingBufferBuilder {
+    fn default() -> Self {
+        Self {
+            max_records: 100,
+            max_bytes: 1024 * 1024,
+            dump_on: None,
+        }
+    }
+}
+
+impl RingBufferBuilder {
+    /// Set the maximum number of records kept in memory. It defaults to 100, as in Bunyan.
+    pub fn max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records.max(1);
+        self
+    }
+
+    /// Set the maximum total size of the records kept in memory, in bytes.
+    /// It defaults to 1 MiB.
+    ///
+    /// Records larger than this on their own are not kept.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Dump the records to the writer returned by `make_writer` every time a record at
+    /// `level` or above is written, e.g. `Level::ERROR` to get the records leading up
+    /// to each error.
+    ///
+    /// The records dumped, including the triggering one, are removed from the buffer, so
+    /// that consecutive errors don't dump the same records twice.
+    pub fn dump_on<M>(mut self, level: Level, make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.dump_on = Some((level, BoxMakeWriter::new(make_writer)));
+        self
+    }
+
+    /// Build the [`RingBuffer`].
+    pub fn build(self) -> RingBuffer {
+        RingBuffer {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                max_records: self.max_records,
+                max_bytes: self.max_bytes,
+                dump_on: self.dump_on,
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for RingBufferBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RingBufferBuilder")
+            .field("max_records", &self.max_records)
+            .field("max_bytes", &self.max_bytes)
+            .field("dump_on", &self.dump_on.as_ref().map(|(level, _)| level))
+            .finish()
+    }
+}
+
+/// A writer keeping the most recent records in memory, to dump them on demand, e.g. for
+/// post-mortem analysis, like Bunyan's `RingBuffer` stream
+/// (see https://github.com/trentm/node-bunyan#raw--ringbuffer-stream ).
+///
+/// Each call to `write` is treated as a complete record, which is how
+/// [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) writes them.
+/// The oldest records are evicted when the buffer holds too many records or too many bytes.
+///
+/// Clones share the same buffer: keep one to dump it, and combine it with other writers
+/// with [`Streams`](crate::Streams), e.g. to keep `debug` records in memory only.
+///
+/// ```rust
+/// use tracing::Level;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, RingBuffer, Stream, Streams};
+///
+/// let ring_buffer = RingBuffer::new(1000);
+/// let streams = Streams::default()
+///     .stream(Stream::new(std::io::stdout).level(Level::INFO))
+///     .stream(Stream::new(ring_buffer.clone()));
+/// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), streams);
+/// // [...] Later on, e.g. from an admin endpoint.
+/// ring_buffer.dump(std::io::stderr()).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct RingBuffer {
+    shared: Arc<Shared>,
+}
+
+impl RingBuffer {
+    /// A ring buffer keeping the last `max_records` records, using the default configuration
+    /// of [`RingBufferBuilder`] otherwise.
+    pub fn new(max_records: usize) -> Self {
+        RingBufferBuilder::default()
+            .max_records(max_records)
+            .build()
+    }
+
+    /// A copy of the records in the buffer, from the oldest to the most recent one.
+    pub fn records(&self) -> Vec<Vec<u8>> {
+        self.shared.lock().records.iter().cloned().collect()
+    }
+
+    /// The number of records in the buffer.
+    pub fn len(&self) -> usize {
+        self.shared.lock().records.len()
+    }
+
+    /// Check if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the records from the buffer.
+    pub fn clear(&self) {
+        let mut state = self.shared.lock();
+        state.records.clear();
+        state.bytes = 0;
+    }
+
+    /// Write all the records in the buffer to `writer`, from the oldest to the most recent one.
+    ///
+    /// The records are kept in the buffer.
+    pub fn dump(&self, writer: impl Write) -> io::Result<()> {
+        write_records(writer, self.records())
+    }
+
+    /// Dump the records to the writer returned by `make_writer` when the current process panics,
+    /// after running the panic hook that was previously installed.
+    pub fn dump_on_panic<M>(&self, make_writer: M)
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let ring_buffer = self.clone();
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous_hook(info);
+            let _ = ring_buffer.dump(make_writer.make_writer());
+        }));
+    }
+}
+
+impl<'a> MakeWriter<'a> for RingBuffer {
+    type Writer = RingBufferWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RingBufferWriter {
+            shared: &self.shared,
+            level: None,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RingBufferWriter {
+            shared: &self.shared,
+            level: Some(*meta.level()),
+        }
+    }
+}
+
+/// The writer returned by [`RingBuffer`], adding records to the buffer.
+#[derive(Debug)]
+pub struct RingBufferWriter<'a> {
+    shared: &'a Shared,
+    /// The level of the record, if known.
+    level: Option<Level>,
+}
+
+impl Write for RingBufferWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.push(buf.to_vec(), self.level)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    records: VecDeque<Vec<u8>>,
+    /// The total size of `records`.
+    bytes: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    max_records: usize,
+    max_bytes: usize,
+    dump_on: Option<(Level, BoxMakeWriter)>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The lock is never held while writing records: the state is consistent even if
+        // another thread panicked while holding it.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, record: Vec<u8>, level: Option<Level>) -> io::Result<()> {
+        let mut state = self.lock();
+        if record.len() <= self.max_bytes {
+            while state.records.len() >= self.max_records
+                || state.bytes + record.len() > self.max_bytes
+            {
+                match state.records.pop_front() {
+                    Some(evicted) => state.bytes -= evicted.len(),
+                    None => break,
+                }
+            }
+            state.bytes += record.len();
+            state.records.push_back(record);
+        }
+
+        match (&self.dump_on, level) {
+            (Some((threshold, make_writer)), Some(level)) if level <= *threshold => {
+                state.bytes = 0;
+                let records = std::mem::take(&mut state.records);
+                drop(state);
+                write_records(make_writer.make_writer(), records)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("state", &self.state)
+            .field("max_records", &self.max_records)
+            .field("max_bytes", &self.max_bytes)
+            .field("dump_on", &self.dump_on.as_ref().map(|(level, _)| level))
+            .finish()
+    }
+}
+
+fn write_records(
+    mut writer: impl Write,
+    records: impl IntoIterator<Item = Vec<u8>>,
+) -> io::Result<()> {
+    for record in records {
+        writer.write_all(&record)?;
+    }
+    writer.flush()
+}
@@ -0,0 +1,202 @@
+use crate::buffers::with_record_buffer;
+use crate::error_handler::FormattingErrorKind;
+use crate::formatting_layer::Type;
+use crate::level::BunyanLevel;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::{self, Write};
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A destination for the records of [`BunyanFormattingLayer`](crate::BunyanFormattingLayer),
+/// which receives them before they are serialized, like Bunyan's "raw" streams
+/// (see https://github.com/trentm/node-bunyan#stream-type-raw ).
+///
+/// Every [`MakeWriter`] is a `RecordSink`, writing each record as a line of JSON.
+///
+/// ```rust
+/// use std::sync::Mutex;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, Record, RecordSink, SinkError};
+///
+/// /// Keep the messages of all records in memory.
+/// #[derive(Default)]
+/// struct Messages(Mutex<Vec<String>>);
+///
+/// impl RecordSink for Messages {
+///     fn write_record(&self, record: &Record<'_>) -> Result<(), SinkError> {
+///         self.0.lock().unwrap().push(record.message().to_owned());
+///         Ok(())
+///     }
+/// }
+///
+/// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), Messages::default());
+/// ```
+pub trait RecordSink: 'static {
+    /// Handle a finished record.
+    ///
+    /// It is called on the thread emitting the record: avoid emitting spans or events
+    /// from it. Errors are reported to the
+    /// [`ErrorHandler`](crate::ErrorHandler) of the layer.
+    fn write_record(&self, record: &Record<'_>) -> Result<(), SinkError>;
+}
+
+/// Write each record as a line of JSON to the writer returned by the `MakeWriter`.
+impl<W> RecordSink for W
+where
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn write_record(&self, record: &Record<'_>) -> Result<(), SinkError> {
+        with_record_buffer(|buffer| {
+            record
+                .write_json(buffer)
+                .map_err(SinkError::serialization)?;
+            // We add a trailing new line.
+            buffer.push(b'\n');
+            // If we write to the writer in more than one go we can end up with
+            // broken/incoherent bits and pieces of records when running
+            // multi-threaded/concurrent programs.
+            self.make_writer_for(record.metadata())
+                .write_all(buffer)
+                .map_err(|error| SinkError::io(error).with_record(buffer))
+        })
+    }
+}
+
+/// The error returned by a [`RecordSink`] which failed to handle a record.
+#[derive(Debug)]
+pub struct SinkError {
+    kind: FormattingErrorKind,
+    error: io::Error,
+    record: Option<Vec<u8>>,
+}
+
+impl SinkError {
+    /// The record could not be serialized.
+    pub fn serialization(error: io::Error) -> Self {
+        Self {
+            kind: FormattingErrorKind::Serialization,
+            error,
+            record: None,
+        }
+    }
+
+    /// The record could not be written.
+    pub fn io(error: io::Error) -> Self {
+        Self {
+            kind: FormattingErrorKind::Io,
+            error,
+            record: None,
+        }
+    }
+
+    /// Attach the serialized record, which is handed over to the
+    /// [`ErrorHandler`](crate::ErrorHandler), e.g. to write it to a fallback writer.
+    pub fn with_record(mut self, record: &[u8]) -> Self {
+        self.record = Some(record.to_vec());
+        self
+    }
+
+    pub(crate) fn kind(&self) -> FormattingErrorKind {
+        self.kind
+    }
+
+    pub(crate) fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    pub(crate) fn record(&self) -> Option<&[u8]> {
+        self.record.as_deref()
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Serializes the fields of a record, for a specific span or event.
+pub(crate) trait RecordFields {
+    /// Serialize the whole record as a JSON object, appending it to `buffer`.
+    fn write_json(&self, record: &Record<'_>, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Build the whole record as a JSON map.
+    fn to_json_map(&self, record: &Record<'_>) -> io::Result<Map<String, Value>>;
+}
+
+/// A finished record, handed over to a [`RecordSink`].
+///
+/// Core fields are available as is, while the whole record, including the fields of the
+/// span or event merged with the default fields, is built on demand with
+/// [`Record::to_json_map`] or [`Record::write_json`].
+pub struct Record<'a> {
+    pub(crate) ty: Type,
+    pub(crate) metadata: &'static Metadata<'static>,
+    pub(crate) span: Option<&'static Metadata<'static>>,
+    pub(crate) level: BunyanLevel,
+    pub(crate) message: &'a str,
+    pub(crate) time: &'a str,
+    pub(crate) fields: &'a dyn RecordFields,
+}
+
+impl<'a> Record<'a> {
+    /// What the record is about: a point in the lifecycle of a span or an event.
+    pub fn ty(&self) -> Type {
+        self.ty
+    }
+
+    /// The metadata of the span or the event the record is about.
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+
+    /// The metadata of the span the record belongs to: the span itself for span records,
+    /// the span the event was emitted in for events, if any.
+    pub fn span(&self) -> Option<&'static Metadata<'static>> {
+        self.span
+    }
+
+    /// The Bunyan level of the record (`level`).
+    pub fn level(&self) -> BunyanLevel {
+        self.level
+    }
+
+    /// The message of the record (`msg`), e.g. `[MY_SPAN - START]` for a span record.
+    pub fn message(&self) -> &'a str {
+        self.message
+    }
+
+    /// The formatted timestamp of the record (`time`).
+    pub fn time(&self) -> &'a str {
+        self.time
+    }
+
+    /// Build the whole record, as it would be serialized to JSON.
+    pub fn to_json_map(&self) -> io::Result<Map<String, Value>> {
+        self.fields.to_json_map(self)
+    }
+
+    /// Serialize the whole record as a JSON object, without a trailing new line, appending
+    /// it to `buffer`.
+    pub fn write_json(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        self.fields.write_json(self, buffer)
+    }
+}
+
+impl fmt::Debug for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Record")
+            .field("ty", &self.ty)
+            .field("metadata", &self.metadata)
+            .field("level", &self.level)
+            .field("message", &self.message)
+            .field("time", &self.time)
+            .finish_non_exhaustive()
+    }
+}
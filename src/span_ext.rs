@@ -0,0 +1,131 @@
+use crate::formatting_layer::OwnFields;
+use crate::storage_layer::JsonStorage;
+use serde_json::{Map, Value};
+use tracing::{Dispatch, Id, Span, Subscriber};
+use tracing_subscriber::registry::{ExtensionsMut, LookupSpan};
+
+/// Extends [`Span`] to read and edit the fields stored for it by
+/// [`JsonStorageLayer`](crate::JsonStorageLayer), e.g. to attach a JSON value discovered while
+/// the span is running, which doesn't have to be declared when the span is created.
+///
+/// Later span records and events emitted inside the span include the changes, while child
+/// spans only see the ones made before they were created, as they get a snapshot of the
+/// fields of their parent. Nothing happens if the span is disabled or if `JsonStorageLayer` is not
+/// registered.
+///
+/// ```rust
+/// use serde_json::json;
+/// use tracing_bunyan_formatter::SpanExt;
+///
+/// let span = tracing::info_span!("checkout");
+/// let _enter = span.enter();
+/// // [...] Once the cart has been loaded.
+/// span.insert_json_field("cart", json!({"items": 3, "total": 42.5}));
+/// tracing::info!("Paying"); // {..., "cart": {"items": 3, "total": 42.5}}
+/// ```
+pub trait SpanExt {
+    /// A snapshot of the fields of the span, including the ones inherited from its parents,
+    /// or `None` if the span is disabled or `JsonStorageLayer` is not registered.
+    ///
+    /// ```rust
+    /// use tracing::Span;
+    /// use tracing_bunyan_formatter::SpanExt;
+    ///
+    /// // e.g. to put the identifier of the request in the body of an error response.
+    /// let request_id = Span::current()
+    ///     .json_fields()
+    ///     .and_then(|mut fields| fields.remove("request_id"));
+    /// ```
+    fn json_fields(&self) -> Option<Map<String, Value>>;
+
+    /// Insert `value` under `key`, returning the value it replaces, if any.
+    fn insert_json_field(&self, key: &'static str, value: Value) -> Option<Value>;
+
+    /// Remove the value stored under `key`, returning it.
+    fn remove_json_field(&self, key: &str) -> Option<Value>;
+}
+
+impl SpanExt for Span {
+    fn json_fields(&self) -> Option<Map<String, Value>> {
+        let mut fields = None;
+        with_extensions(self, &mut |extensions| {
+            fields = extensions.get_mut::<JsonStorage>().map(|storage| {
+                storage
+                    .iter()
+                    .map(|(key, value)| (key.to_owned(), value.clone()))
+                    .collect()
+            });
+        });
+        fields
+    }
+
+    fn insert_json_field(&self, key: &'static str, value: Value) -> Option<Value> {
+        let mut value = Some(value);
+        let mut previous = None;
+        with_extensions(self, &mut |extensions| {
+            let value = match value.take() {
+                Some(value) => value,
+                None => return,
+            };
+            if let Some(fields) = extensions.get_mut::<OwnFields>() {
+                fields.0.insert(key, value.clone());
+            }
+            if let Some(storage) = extensions.get_mut::<JsonStorage>() {
+                previous = storage.get(key).cloned();
+                storage.insert(key, value);
+            }
+        });
+        previous
+    }
+
+    fn remove_json_field(&self, key: &str) -> Option<Value> {
+        let mut removed = None;
+        with_extensions(self, &mut |extensions| {
+            if let Some(fields) = extensions.get_mut::<OwnFields>() {
+                fields.0.remove(key);
+            }
+            if let Some(storage) = extensions.get_mut::<JsonStorage>() {
+                removed = storage.remove(key);
+            }
+        });
+        removed
+    }
+}
+
+/// Run `f` with the extensions of `span`, if it is enabled and `JsonStorageLayer` is registered.
+fn with_extensions(span: &Span, f: ExtensionsFn<'_>) {
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(with_extensions) = dispatch.downcast_ref::<WithExtensions>() {
+            (with_extensions.0)(dispatch, id, f);
+        }
+    });
+}
+
+/// Gives access to the extensions of a span without knowing the type of the subscriber,
+/// exposed by [`JsonStorageLayer`](crate::JsonStorageLayer) through `downcast_raw`.
+#[derive(Clone, Copy)]
+pub(crate) struct WithExtensions(fn(&Dispatch, &Id, ExtensionsFn<'_>));
+
+type ExtensionsFn<'a> = &'a mut dyn FnMut(&mut ExtensionsMut<'_>);
+
+impl WithExtensions {
+    /// The implementation for the subscriber `JsonStorageLayer` is layered onto.
+    pub(crate) fn of<S>() -> &'static Self
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        &WithExtensions(with_span_extensions::<S>)
+    }
+}
+
+fn with_span_extensions<S>(dispatch: &Dispatch, id: &Id, f: ExtensionsFn<'_>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let span = dispatch
+        .downcast_ref::<S>()
+        .and_then(|subscriber| subscriber.span(id));
+    if let Some(span) = span {
+        f(&mut span.extensions_mut());
+    }
+}
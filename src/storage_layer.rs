@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use crate::span_ext::WithExtensions;
+use ahash::HashMapExt;
+use once_cell::sync::OnceCell;
+use std::any::TypeId;
+use std::collections::{hash_map, HashMap};
 use std::fmt;
+use std::sync::Arc;
 use std::time::Instant;
 use tracing::field::{Field, Visit};
 use tracing::span::{Attributes, Record};
 use tracing::{Id, Subscriber};
 use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::SpanRef;
 use tracing_subscriber::Layer;
 
 /// This layer is only concerned with information storage, it does not do any formatting or provide any output.
@@ -25,15 +31,131 @@ pub struct JsonStorageLayer;
 ///
 /// For spans, we also store the duration of each span with the `elapsed_milliseconds` key using
 /// the `on_exit`/`on_enter` handlers.
-#[derive(Clone, Debug)]
+///
+/// The storage of a span doesn't copy the fields of its parent: it points to the storage of
+/// the parent, and records are formatted by walking the chain of storages. The merged set of
+/// fields is only materialized (and cached) if it is requested with [`JsonStorage::values`].
+/// Changes made to the storage of a span after a child was created are not visible to the
+/// child.
+///
+/// This makes creating spans below spans with many fields cheaper, but not formatting them:
+/// a span record still walks every field inherited by the span, so emitting span records
+/// costs about as much as when the fields were copied.
+#[derive(Clone)]
 pub struct JsonStorage<'a> {
-    values: HashMap<&'a str, serde_json::Value>,
+    values: Values<'a>,
+}
+
+#[derive(Clone)]
+enum Values<'a> {
+    /// Values stored on their own, e.g. the fields of an event.
+    Flat(HashMap<&'a str, serde_json::Value>),
+    /// The values of a span, linked to the ones of its parents.
+    Linked(Arc<Node>),
 }
 
 impl<'a> JsonStorage<'a> {
     /// Get the set of stored values, as a set of keys and JSON values.
     pub fn values(&self) -> &HashMap<&'a str, serde_json::Value> {
-        &self.values
+        match &self.values {
+            Values::Flat(values) => values,
+            Values::Linked(node) => node.merged(),
+        }
+    }
+
+    /// Iterate over the stored values without materializing them: each key is yielded once,
+    /// with the value closest to the span.
+    pub(crate) fn iter(&self) -> Iter<'_, 'a> {
+        Iter(match &self.values {
+            Values::Flat(values) => IterState::Flat(values.iter()),
+            Values::Linked(node) => IterState::Linked {
+                own: node.own.iter(),
+                inherited: node
+                    .parent
+                    .as_deref()
+                    .map(|parent| (parent, parent.own.iter())),
+                visible: node.inherited().iter(),
+            },
+        })
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    pub(crate) fn get_key_value(&self, key: &str) -> Option<(&'a str, &serde_json::Value)> {
+        match &self.values {
+            Values::Flat(values) => values.get_key_value(key).map(|(key, value)| (*key, value)),
+            Values::Linked(node) => node.get_key_value(key),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &'static str, value: serde_json::Value) {
+        match &mut self.values {
+            Values::Flat(values) => {
+                values.insert(key, value);
+            }
+            Values::Linked(node) => {
+                // A child span might still share it, e.g. when the parent is closed before the
+                // storage of the child is dropped: link a new node to it instead of copying it.
+                if Arc::get_mut(node).is_none() {
+                    *node = Arc::new(Node::child_of(Some(node.clone())));
+                }
+                let node = Arc::make_mut(node);
+                if let Some(merged) = node.merged.get_mut() {
+                    merged.insert(key, value.clone());
+                }
+                // The parents can't change anymore: nodes shared with a child are never modified.
+                if node.own.insert(key, value).is_none() {
+                    if let Some(distance) = node.parent.as_ref().and_then(|p| p.distance(key)) {
+                        node.overrides.push((key, distance));
+                        node.inherited.take();
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<serde_json::Value> {
+        self.get(key)?;
+        match &mut self.values {
+            Values::Flat(values) => values.remove(key),
+            Values::Linked(node) => {
+                // Inherited values can't be removed from the storage of the parents:
+                // detach it.
+                let mut values = node.materialize();
+                let removed = values.remove(key);
+                self.values = Values::Linked(Arc::new(Node::root(values)));
+                removed
+            }
+        }
+    }
+}
+
+impl JsonStorage<'static> {
+    /// The initial storage of a root span.
+    pub(crate) fn root() -> Self {
+        Self::linked(None)
+    }
+
+    /// The initial storage of a child span, inheriting the values stored so far.
+    pub(crate) fn child(&self) -> Self {
+        // Spans without fields of their own are skipped, to keep chains short.
+        let parent = match &self.values {
+            Values::Flat(values) if values.is_empty() => None,
+            Values::Flat(values) => {
+                Some(Arc::new(Node::root(values.clone().into_iter().collect())))
+            }
+            Values::Linked(node) if node.own.is_empty() => node.parent.clone(),
+            Values::Linked(node) => Some(node.clone()),
+        };
+        Self::linked(parent)
+    }
+
+    fn linked(parent: Option<Arc<Node>>) -> Self {
+        Self {
+            values: Values::Linked(Arc::new(Node::child_of(parent))),
+        }
     }
 }
 
@@ -41,7 +163,218 @@ impl<'a> JsonStorage<'a> {
 impl Default for JsonStorage<'_> {
     fn default() -> Self {
         Self {
-            values: HashMap::new(),
+            values: Values::Flat(HashMap::new()),
+        }
+    }
+}
+
+impl fmt::Debug for JsonStorage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonStorage")
+            .field("values", self.values())
+            .finish()
+    }
+}
+
+/// The values of a [`JsonStorage`], see [`JsonStorage::iter`].
+pub(crate) struct Iter<'s, 'a>(IterState<'s, 'a>);
+
+enum IterState<'s, 'a> {
+    Flat(hash_map::Iter<'s, &'a str, serde_json::Value>),
+    Linked {
+        own: hash_map::Iter<'s, &'static str, serde_json::Value>,
+        /// The parent being walked and its remaining entries.
+        inherited: Option<(
+            &'s Node,
+            hash_map::Iter<'s, &'static str, serde_json::Value>,
+        )>,
+        visible: std::slice::Iter<'s, bool>,
+    },
+}
+
+impl<'s, 'a> Iterator for Iter<'s, 'a> {
+    type Item = (&'a str, &'s serde_json::Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            IterState::Flat(entries) => entries.next().map(|(key, value)| (*key, value)),
+            IterState::Linked {
+                own,
+                inherited,
+                visible,
+            } => {
+                if let Some((key, value)) = own.next() {
+                    return Some((*key, value));
+                }
+                loop {
+                    let (parent, entries) = inherited.as_mut()?;
+                    match entries.next() {
+                        Some((key, value)) => {
+                            if *visible.next()? {
+                                return Some((*key, value));
+                            }
+                        }
+                        None => {
+                            let parent: &'s Node = parent;
+                            let next = parent.parent.as_deref()?;
+                            *inherited = Some((next, next.own.iter()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The maximum number of nodes in a chain of storages.
+const MAX_CHAIN_LENGTH: usize = 8;
+
+/// The values stored by a [`Node`]: hashed with `ahash`, as they are looked up in every node
+/// of the chain when values are inserted.
+type NodeValues = ahash::HashMap<&'static str, serde_json::Value>;
+
+/// A link in the chain of storages going from a span up to its root span.
+#[derive(Clone)]
+struct Node {
+    /// The values recorded on the span itself, overriding the ones of its parents.
+    own: NodeValues,
+    /// The keys of `own` which are also stored by the parents, with the distance to the
+    /// closest parent storing them.
+    overrides: Vec<(&'static str, usize)>,
+    /// The number of nodes in the chain, up to the root span.
+    length: usize,
+    parent: Option<Arc<Node>>,
+    /// `own` merged with the values of the parents, once requested with
+    /// [`JsonStorage::values`].
+    merged: OnceCell<HashMap<&'static str, serde_json::Value>>,
+    /// Whether each entry stored by the parents is yielded by [`JsonStorage::iter`], see
+    /// [`Node::inherited`].
+    inherited: OnceCell<Vec<bool>>,
+}
+
+impl Node {
+    fn root(own: NodeValues) -> Self {
+        Self {
+            own,
+            overrides: Vec::new(),
+            length: 1,
+            parent: None,
+            merged: OnceCell::new(),
+            inherited: OnceCell::new(),
+        }
+    }
+
+    /// An empty node, linked to `parent`.
+    fn child_of(parent: Option<Arc<Node>>) -> Self {
+        // Records are formatted by walking the whole chain: long chains are flattened.
+        let parent = parent.map(|parent| {
+            if parent.length < MAX_CHAIN_LENGTH {
+                parent
+            } else {
+                Arc::new(Node::root(parent.materialize()))
+            }
+        });
+        Self {
+            own: NodeValues::new(),
+            overrides: Vec::new(),
+            length: parent.as_ref().map_or(1, |parent| parent.length + 1),
+            parent,
+            merged: OnceCell::new(),
+            inherited: OnceCell::new(),
+        }
+    }
+
+    fn get_key_value(&self, key: &str) -> Option<(&'static str, &serde_json::Value)> {
+        let mut node = Some(self);
+        while let Some(current) = node {
+            let found = match current.merged.get() {
+                Some(merged) => return merged.get_key_value(key).map(|(k, v)| (*k, v)),
+                None => current.own.get_key_value(key),
+            };
+            if let Some((key, value)) = found {
+                return Some((*key, value));
+            }
+            node = current.parent.as_deref();
+        }
+        None
+    }
+
+    /// Whether each entry stored by the parents, walked from the parent up to the root, is
+    /// yielded by [`JsonStorage::iter`]: entries overridden by a node closer to the span are not.
+    ///
+    /// Built from the one of the parent, which is usually built already for its own records.
+    /// The parents never change, and an unchanged `HashMap` is always iterated in the same order.
+    fn inherited(&self) -> &[bool] {
+        self.inherited.get_or_init(|| {
+            let Some(parent) = &self.parent else {
+                return Vec::new();
+            };
+            let mut visible = Vec::with_capacity(parent.own.len() + parent.inherited().len());
+            visible.resize(parent.own.len(), true);
+            visible.extend_from_slice(parent.inherited());
+            for (key, distance) in &self.overrides {
+                if let Some(position) = parent.position(key, *distance) {
+                    visible[position] = false;
+                }
+            }
+            visible
+        })
+    }
+
+    /// The number of nodes between this one and the closest one storing `key`.
+    fn distance(&self, key: &str) -> Option<usize> {
+        let mut node = self;
+        let mut distance = 0;
+        while !node.own.contains_key(key) {
+            node = node.parent.as_deref()?;
+            distance += 1;
+        }
+        Some(distance)
+    }
+
+    /// The position of `key` among the entries of the chain, walked from this node up to the
+    /// root, given the distance to the node storing it.
+    fn position(&self, key: &str, distance: usize) -> Option<usize> {
+        let mut node = self;
+        let mut offset = 0;
+        for _ in 0..distance {
+            offset += node.own.len();
+            node = node.parent.as_deref()?;
+        }
+        Some(offset + node.own.keys().position(|stored| *stored == key)?)
+    }
+
+    fn merged(&self) -> &HashMap<&'static str, serde_json::Value> {
+        self.merged
+            .get_or_init(|| self.materialize().into_iter().collect())
+    }
+
+    /// `own` merged with the values of the parents.
+    fn materialize(&self) -> NodeValues {
+        let mut chain = Vec::new();
+        let mut node = self;
+        while let Some(parent) = node.parent.as_deref() {
+            chain.push(node);
+            node = parent;
+        }
+        // Cloning a map is cheaper than building it again.
+        let mut merged = node.own.clone();
+        for current in chain.iter().rev() {
+            merged.extend(current.own.iter().map(|(key, value)| (*key, value.clone())));
+        }
+        merged
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // Drop the chain iteratively: a recursive drop could overflow the stack for very
+        // deep span trees.
+        let mut parent = self.parent.take();
+        while let Some(node) = parent {
+            parent = Arc::try_unwrap(node)
+                .ok()
+                .and_then(|mut node| node.parent.take());
         }
     }
 }
@@ -50,32 +383,27 @@ impl Default for JsonStorage<'_> {
 impl Visit for JsonStorage<'_> {
     /// Visit a signed 64-bit integer value.
     fn record_i64(&mut self, field: &Field, value: i64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit an unsigned 64-bit integer value.
     fn record_u64(&mut self, field: &Field, value: u64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit a 64-bit floating point value.
     fn record_f64(&mut self, field: &Field, value: f64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit a boolean value.
     fn record_bool(&mut self, field: &Field, value: bool) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit a string value.
     fn record_str(&mut self, field: &Field, value: &str) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
@@ -83,12 +411,27 @@ impl Visit for JsonStorage<'_> {
             // Skip fields that are actually log metadata that have already been handled
             name if name.starts_with("log.") => (),
             name if name.starts_with("r#") => {
-                self.values
-                    .insert(&name[2..], serde_json::Value::from(format!("{:?}", value)));
+                self.insert(&name[2..], serde_json::Value::from(format!("{:?}", value)));
+            }
+            name => {
+                self.insert(name, serde_json::Value::from(format!("{:?}", value)));
+            }
+        };
+    }
+
+    /// Visit an error, serialized as a Bunyan `err` object: `message`, `stack` and the
+    /// messages of its chain of `source`s.
+    ///
+    /// Name the field `err` (e.g. `error!(err = &e as &dyn Error, "...")`) to let Bunyan
+    /// viewers render it as an error.
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        match field.name() {
+            name if name.starts_with("log.") => (),
+            name if name.starts_with("r#") => {
+                self.insert(&name[2..], error_to_json(value));
             }
             name => {
-                self.values
-                    .insert(name, serde_json::Value::from(format!("{:?}", value)));
+                self.insert(name, error_to_json(value));
             }
         };
     }
@@ -100,7 +443,7 @@ impl Visit for JsonStorage<'_> {
 
         match serde_json::to_value(serializable) {
             Ok(json_value) => {
-                self.values.insert(field.name(), json_value);
+                self.insert(field.name(), json_value);
             }
             Err(error) => {
                 tracing::debug!(
@@ -119,94 +462,185 @@ impl Visit for JsonStorage<'_> {
     }
 }
 
-impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer<S>
-    for JsonStorageLayer
-{
-    /// Span creation.
-    /// This is the only occasion we have to store the fields attached to the span
-    /// given that they might have been borrowed from the surrounding context.
-    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
-
-        // We want to inherit the fields from the parent span, if there is one.
-        let mut visitor = if let Some(parent_span) = span.parent() {
-            // Extensions can be used to associate arbitrary data to a span.
-            // We'll use it to store our representation of its fields.
-            // We create a copy of the parent visitor!
-            let mut extensions = parent_span.extensions_mut();
-            extensions
-                .get_mut::<JsonStorage>()
-                .map(|v| v.to_owned())
-                .unwrap_or_default()
-        } else {
-            JsonStorage::default()
-        };
+/// Bunyan's conventional representation of an error.
+///
+/// Rust errors don't carry a stack trace nor a type name: `stack` lists the messages of the
+/// error and its sources, in the same way `anyhow` does, while `name` is left out.
+pub(crate) fn error_to_json(error: &(dyn std::error::Error + 'static)) -> serde_json::Value {
+    let message = error.to_string();
+    let sources: Vec<String> = std::iter::successors(error.source(), |e| e.source())
+        .map(ToString::to_string)
+        .collect();
+    let mut stack = message.clone();
+    for source in &sources {
+        stack.push_str("\nCaused by: ");
+        stack.push_str(source);
+    }
+    serde_json::json!({
+        "message": message,
+        "stack": stack,
+        "source": sources,
+    })
+}
 
-        let mut extensions = span.extensions_mut();
+/// The initial storage of `span`, inheriting the storage of its parent, if there is one.
+fn inherited_storage<S>(span: &SpanRef<S>) -> JsonStorage<'static>
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    match span.parent() {
+        // Extensions can be used to associate arbitrary data to a span.
+        // We'll use it to store our representation of its fields.
+        // The child shares the storage of its parent, instead of copying it.
+        Some(parent_span) => parent_span
+            .extensions()
+            .get::<JsonStorage>()
+            .map(JsonStorage::child)
+            .unwrap_or_else(JsonStorage::root),
+        None => JsonStorage::root(),
+    }
+}
 
-        // Register all fields.
-        // Fields on the new span should override fields on the parent span if there is a conflict.
-        attrs.record(&mut visitor);
-        // Associate the visitor with the Span for future usage via the Span's extensions
-        extensions.insert(visitor);
+/// Span creation.
+/// This is the only occasion we have to store the fields attached to the span
+/// given that they might have been borrowed from the surrounding context.
+pub(crate) fn store_new_span<S>(attrs: &Attributes<'_>, span: &SpanRef<S>)
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    // Fields might have been stored already by another layer, e.g. `JsonStorageLayer`
+    // registered alongside a formatting layer built with `json_storage(true)`.
+    if span.extensions().get::<JsonStorage>().is_some() {
+        return;
     }
+    // We want to inherit the fields from the parent span, if there is one.
+    let mut visitor = inherited_storage(span);
 
-    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
-        let span = ctx.span(span).expect("Span not found, this is a bug");
-
-        // Before you can associate a record to an existing Span, well, that Span has to be created!
-        // We can thus rely on the invariant that we always associate a JsonVisitor with a Span
-        // on creation (`new_span` method), hence it's safe to unwrap the Option.
-        let mut extensions = span.extensions_mut();
-        let visitor = extensions
-            .get_mut::<JsonStorage>()
-            .expect("Visitor not found on 'record', this is a bug");
+    let mut extensions = span.extensions_mut();
+
+    // Register all fields.
+    // Fields on the new span should override fields on the parent span if there is a conflict.
+    attrs.record(&mut visitor);
+    // Associate the visitor with the Span for future usage via the Span's extensions
+    extensions.insert(visitor);
+}
+
+pub(crate) fn store_record<S>(values: &Record<'_>, span: &SpanRef<S>)
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    // A visitor is associated with each span on creation (`new_span` method), unless the
+    // span was created before this layer was registered: it's created on the fly then.
+    let mut extensions = span.extensions_mut();
+    match extensions.get_mut::<JsonStorage>() {
         // Register all new fields
-        values.record(visitor);
+        Some(visitor) => values.record(visitor),
+        None => {
+            let mut visitor = inherited_storage(span);
+            values.record(&mut visitor);
+            extensions.insert(visitor);
+        }
     }
+}
 
-    /// When we enter a span **for the first time** save the timestamp in its extensions.
-    fn on_enter(&self, span: &Id, ctx: Context<'_, S>) {
-        let span = ctx.span(span).expect("Span not found, this is a bug");
+/// When we enter a span **for the first time** save the timestamp in its extensions.
+pub(crate) fn store_enter<S>(span: &SpanRef<S>)
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let mut extensions = span.extensions_mut();
+    if extensions.get_mut::<Instant>().is_none() {
+        extensions.insert(Instant::now());
+    }
+}
+
+/// When we close a span, register how long it took in milliseconds.
+pub(crate) fn store_close<S>(span: &SpanRef<S>)
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    // Using a block to drop the immutable reference to extensions
+    // given that we want to borrow it mutably just below
+    let elapsed_milliseconds = {
+        let extensions = span.extensions();
+        extensions
+            .get::<Instant>()
+            .map(|i| i.elapsed().as_millis())
+            // If `Instant` is not in the span extensions it means that the span was never
+            // entered into.
+            .unwrap_or(0)
+    };
 
-        let mut extensions = span.extensions_mut();
-        if extensions.get_mut::<Instant>().is_none() {
-            extensions.insert(Instant::now());
+    #[cfg(not(feature = "arbitrary-precision"))]
+    // without the arbitrary_precision feature u128 values are not supported,
+    // but u64 is still more than enough for our purposes
+    let elapsed_milliseconds: u64 = {
+        use std::convert::TryInto;
+
+        elapsed_milliseconds.try_into().unwrap_or_default()
+    };
+
+    let Ok(elapsed) = serde_json::to_value(elapsed_milliseconds) else {
+        return;
+    };
+    let mut extensions_mut = span.extensions_mut();
+    match extensions_mut.get_mut::<JsonStorage>() {
+        Some(visitor) => {
+            visitor.insert("elapsed_milliseconds", elapsed);
+        }
+        None => {
+            let mut visitor = inherited_storage(span);
+            visitor.insert("elapsed_milliseconds", elapsed);
+            extensions_mut.insert(visitor);
         }
     }
+}
 
-    /// When we close a span, register how long it took in milliseconds.
-    fn on_close(&self, span: Id, ctx: Context<'_, S>) {
-        let span = ctx.span(&span).expect("Span not found, this is a bug");
-
-        // Using a block to drop the immutable reference to extensions
-        // given that we want to borrow it mutably just below
-        let elapsed_milliseconds = {
-            let extensions = span.extensions();
-            extensions
-                .get::<Instant>()
-                .map(|i| i.elapsed().as_millis())
-                // If `Instant` is not in the span extensions it means that the span was never
-                // entered into.
-                .unwrap_or(0)
-        };
+/// Expose the extensions of spans to [`SpanExt`](crate::SpanExt) through `downcast_raw`.
+pub(crate) fn downcast_with_extensions<S>(id: TypeId) -> Option<*const ()>
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    if id == TypeId::of::<WithExtensions>() {
+        Some(WithExtensions::of::<S>() as *const WithExtensions as *const ())
+    } else {
+        None
+    }
+}
 
-        #[cfg(not(feature = "arbitrary-precision"))]
-        // without the arbitrary_precision feature u128 values are not supported,
-        // but u64 is still more than enough for our purposes
-        let elapsed_milliseconds: u64 = {
-            use std::convert::TryInto;
+impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer<S>
+    for JsonStorageLayer
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            store_new_span(attrs, &span);
+        }
+    }
 
-            elapsed_milliseconds.try_into().unwrap_or_default()
-        };
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(span) {
+            store_record(values, &span);
+        }
+    }
 
-        let mut extensions_mut = span.extensions_mut();
-        let visitor = extensions_mut
-            .get_mut::<JsonStorage>()
-            .expect("Visitor not found on 'record', this is a bug");
+    fn on_enter(&self, span: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(span) {
+            store_enter(&span);
+        }
+    }
 
-        if let Ok(elapsed) = serde_json::to_value(elapsed_milliseconds) {
-            visitor.values.insert("elapsed_milliseconds", elapsed);
+    fn on_close(&self, span: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&span) {
+            store_close(&span);
+        }
+    }
+
+    /// Expose the extensions of spans to [`SpanExt`](crate::SpanExt).
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else {
+            downcast_with_extensions::<S>(id)
         }
     }
 }
@@ -0,0 +1,137 @@
+use crate::buffers::with_record_buffer;
+use crate::level::BunyanLevel;
+use crate::sink::{Record, RecordSink, SinkError};
+use std::fmt;
+use std::io::Write;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A destination for the records of a [`Streams`] writer, with its own minimum level and,
+/// optionally, its own set of targets.
+///
+/// ```rust
+/// use tracing::Level;
+/// use tracing_bunyan_formatter::Stream;
+///
+/// // `warn` and `error` records emitted by `my_app` (or its modules) only.
+/// let stream = Stream::new(std::io::stderr)
+///     .level(Level::WARN)
+///     .target("my_app");
+/// ```
+pub struct Stream {
+    make_writer: BoxMakeWriter,
+    level: Option<BunyanLevel>,
+    targets: Vec<String>,
+}
+
+impl Stream {
+    /// A stream writing all records to the writers returned by `make_writer`.
+    pub fn new<M>(make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            make_writer: BoxMakeWriter::new(make_writer),
+            level: None,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Only write records at `level` or above, e.g. `Level::WARN` for `warn`, `error` and
+    /// `fatal` records.
+    ///
+    /// The Bunyan level of the record is compared, as emitted: it accounts for the reserved
+    /// `bunyan.level` field and the custom
+    /// [`level_mapping`](crate::BunyanFormattingLayerBuilder::level_mapping) of the layer.
+    /// All records are written by default.
+    pub fn level(mut self, level: impl Into<BunyanLevel>) -> Self {
+        self.level = Some(level.into());
+        self
+    }
+
+    /// Only write records whose target is `target` or one of its modules, e.g. `my_app`
+    /// matches both `my_app` and `my_app::http`, but not `my_application`.
+    ///
+    /// Calling it more than once adds targets: records matching any of them are written.
+    /// Records are written whatever their target by default.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.targets.push(target.into());
+        self
+    }
+
+    fn accepts(&self, record: &Record<'_>) -> bool {
+        let target = record.metadata().target();
+        self.level.map_or(true, |level| record.level() >= level)
+            && (self.targets.is_empty()
+                || self.targets.iter().any(|prefix| {
+                    matches!(
+                        target.strip_prefix(prefix.as_str()),
+                        Some(rest) if rest.is_empty() || rest.starts_with("::")
+                    )
+                }))
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stream")
+            .field("make_writer", &self.make_writer)
+            .field("level", &self.level)
+            .field("targets", &self.targets)
+            .finish()
+    }
+}
+
+/// A writer sending each record to several [`Stream`]s, as Bunyan loggers do
+/// (see https://github.com/trentm/node-bunyan#streams ).
+///
+/// The record is serialized once, then written to all the streams accepting its level and
+/// target: `Streams` is a [`RecordSink`], routing records on their Bunyan level.
+///
+/// ```rust
+/// use tracing::Level;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, Stream, Streams};
+///
+/// // Everything to stdout, `warn` and `error` records to stderr as well.
+/// let streams = Streams::default()
+///     .stream(Stream::new(std::io::stdout))
+///     .stream(Stream::new(std::io::stderr).level(Level::WARN));
+/// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), streams);
+/// ```
+#[derive(Debug, Default)]
+pub struct Streams {
+    streams: Vec<Stream>,
+}
+
+impl Streams {
+    /// Add a stream.
+    pub fn stream(mut self, stream: Stream) -> Self {
+        self.streams.push(stream);
+        self
+    }
+}
+
+/// Serialize the record once, then write it in full to every stream accepting it, even if
+/// some of them fail. The first error is reported.
+impl RecordSink for Streams {
+    fn write_record(&self, record: &Record<'_>) -> Result<(), SinkError> {
+        if !self.streams.iter().any(|stream| stream.accepts(record)) {
+            return Ok(());
+        }
+        with_record_buffer(|buffer| {
+            record
+                .write_json(buffer)
+                .map_err(SinkError::serialization)?;
+            buffer.push(b'\n');
+            let mut result = Ok(());
+            for stream in self.streams.iter().filter(|stream| stream.accepts(record)) {
+                let written = stream
+                    .make_writer
+                    .make_writer_for(record.metadata())
+                    .write_all(buffer);
+                result = result.and(written);
+            }
+            result.map_err(|error| SinkError::io(error).with_record(buffer))
+        })
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+use time::{Duration, OffsetDateTime, UtcOffset};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+
+/// The number of fractional digits used for the seconds of a [`BunyanTime`] timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampPrecision {
+    /// Up to nanosecond precision, trimming trailing zeros (e.g. `2023-03-29T18:34:38.4454Z`).
+    ///
+    /// This is the default.
+    #[default]
+    Auto,
+    /// Always three fractional digits (e.g. `2023-03-29T18:34:38.445Z`).
+    Millis,
+    /// Always six fractional digits (e.g. `2023-03-29T18:34:38.445454Z`).
+    Micros,
+    /// Always nine fractional digits (e.g. `2023-03-29T18:34:38.445454908Z`).
+    Nanos,
+}
+
+#[derive(Clone, Debug)]
+enum Clock {
+    System,
+    Frozen(OffsetDateTime),
+}
+
+/// The default timer of [`BunyanFormattingLayer`](crate::BunyanFormattingLayer): it formats
+/// the `time` field of each record as an [RFC 3339](https://datatracker.ietf.org/doc/html/rfc3339)
+/// timestamp, as required by the [Bunyan format](https://github.com/trentm/node-bunyan#core-fields).
+///
+/// It implements [`FormatTime`], hence it can be swapped for any other timer from the
+/// `tracing_subscriber` ecosystem via [`BunyanFormattingLayer::timer`](crate::BunyanFormattingLayer::timer).
+///
+/// ```rust
+/// use time::macros::datetime;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, BunyanTime, TimestampPrecision};
+///
+/// // Always use millisecond precision.
+/// let formatting_layer = BunyanFormattingLayer::new("test".into(), std::io::stdout)
+///     .timer(BunyanTime::utc().precision(TimestampPrecision::Millis));
+///
+/// // Stop the clock, useful to get deterministic records in tests.
+/// let formatting_layer = BunyanFormattingLayer::new("test".into(), std::io::stdout)
+///     .timer(BunyanTime::frozen(datetime!(2020-01-01 0:00 UTC)));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BunyanTime {
+    clock: Clock,
+    offset: UtcOffset,
+    precision: TimestampPrecision,
+}
+
+impl BunyanTime {
+    /// Timestamps from the system clock, in UTC.
+    pub fn utc() -> Self {
+        Self::with_offset(UtcOffset::UTC)
+    }
+
+    /// Timestamps from the system clock, using the local offset of the machine.
+    ///
+    /// The local offset is determined once, when this method is called: it returns
+    /// an error if it can't be determined soundly (e.g. on Unix, when other threads
+    /// are already running).
+    #[cfg(feature = "local-time")]
+    pub fn local() -> Result<Self, time::error::IndeterminateOffset> {
+        UtcOffset::current_local_offset().map(Self::with_offset)
+    }
+
+    /// Timestamps from the system clock, using a fixed offset from UTC.
+    pub fn with_offset(offset: UtcOffset) -> Self {
+        Self {
+            clock: Clock::System,
+            offset,
+            precision: TimestampPrecision::default(),
+        }
+    }
+
+    /// Always report the same instant, using its offset.
+    pub fn frozen(at: OffsetDateTime) -> Self {
+        Self {
+            offset: at.offset(),
+            clock: Clock::Frozen(at),
+            precision: TimestampPrecision::default(),
+        }
+    }
+
+    /// Set the number of fractional digits used for the seconds.
+    pub fn precision(mut self, precision: TimestampPrecision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    fn now(&self) -> OffsetDateTime {
+        match &self.clock {
+            Clock::System => OffsetDateTime::now_utc().to_offset(self.offset),
+            Clock::Frozen(at) => *at,
+        }
+    }
+}
+
+impl Default for BunyanTime {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+impl FormatTime for BunyanTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        write_rfc3339(w, self.now(), self.precision)
+    }
+}
+
+/// Move `at` to its offset rounded to the closest minute.
+///
+/// `at` is left untouched at the boundaries of the supported range.
+fn round_offset(at: OffsetDateTime) -> OffsetDateTime {
+    let seconds = at.offset().whole_seconds();
+    let rounded = (seconds + 30 * seconds.signum()) / 60 * 60;
+    // Offsets can't reach 26 hours: round towards zero if needed.
+    let rounded = UtcOffset::from_whole_seconds(rounded)
+        .or_else(|_| UtcOffset::from_whole_seconds(seconds / 60 * 60));
+    match rounded {
+        Ok(rounded) => at
+            .checked_add(Duration::seconds(i64::from(
+                rounded.whole_seconds() - seconds,
+            )))
+            .map_or(at, |local| local.replace_offset(rounded)),
+        Err(_) => at,
+    }
+}
+
+/// Write `at` as an RFC 3339 timestamp.
+///
+/// Unlike `time`'s `Rfc3339` formatter, it never fails for years outside of `0..=9999`, nor
+/// for offsets with seconds, which RFC 3339 can't represent: they are rounded to the closest
+/// minute, keeping the same instant.
+fn write_rfc3339(
+    w: &mut impl fmt::Write,
+    at: OffsetDateTime,
+    precision: TimestampPrecision,
+) -> fmt::Result {
+    let at = if at.offset().seconds_past_minute() == 0 {
+        at
+    } else {
+        round_offset(at)
+    };
+    write!(
+        w,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        at.year(),
+        u8::from(at.month()),
+        at.day(),
+        at.hour(),
+        at.minute(),
+        at.second()
+    )?;
+
+    let nanos = at.nanosecond();
+    match precision {
+        TimestampPrecision::Auto if nanos == 0 => {}
+        TimestampPrecision::Auto => {
+            let mut digits = 9;
+            let mut subsecond = nanos;
+            while subsecond % 10 == 0 {
+                subsecond /= 10;
+                digits -= 1;
+            }
+            write!(w, ".{:0width$}", subsecond, width = digits)?;
+        }
+        TimestampPrecision::Millis => write!(w, ".{:03}", nanos / 1_000_000)?,
+        TimestampPrecision::Micros => write!(w, ".{:06}", nanos / 1_000)?,
+        TimestampPrecision::Nanos => write!(w, ".{:09}", nanos)?,
+    }
+
+    let offset = at.offset();
+    if offset.is_utc() {
+        w.write_char('Z')
+    } else {
+        let sign = if offset.is_negative() { '-' } else { '+' };
+        write!(
+            w,
+            "{}{:02}:{:02}",
+            sign,
+            offset.whole_hours().unsigned_abs(),
+            offset.minutes_past_hour().unsigned_abs()
+        )
+    }
+}
@@ -1,11 +1,20 @@
-use crate::mock_writer::MockWriter;
+use crate::mock_writer::{MockMakeWriter, MockWriter};
 use claims::assert_some_eq;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use time::format_description::well_known::Rfc3339;
+use time::macros::{datetime, offset};
 use tracing::{info, span, Level};
-use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_bunyan_formatter::{
+    BuildError, BunyanFormattingLayer, BunyanFormattingLayerBuilder, BunyanLevel, BunyanTime,
+    ErrorCounters, ErrorHandler, FieldOrdering, FormattingErrorKind, JsonStorage, JsonStorageLayer,
+    KeyCollisionPolicy, NonBlockingBuilder, OverflowPolicy, PanicHook, Record, RecordSink,
+    Redaction, RedactionRule, RingBuffer, RingBufferBuilder, SinkError, SourceLocation, SpanExt,
+    SpanIds, Stream, Streams, TimestampPrecision, Type,
+};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::Registry;
 
@@ -48,6 +57,83 @@ fn run_and_get_output<F: Fn()>(action: F) -> Vec<Value> {
         .collect()
 }
 
+// Run a closure and collect the raw output emitted by a `BunyanFormattingLayer` configured
+// by `configure`.
+fn run_and_get_raw_output_with<C, F>(configure: C, action: F) -> String
+where
+    C: FnOnce(
+        BunyanFormattingLayerBuilder<MockMakeWriter>,
+    ) -> BunyanFormattingLayerBuilder<MockMakeWriter>,
+    F: Fn(),
+{
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let formatting_layer = configure(BunyanFormattingLayer::builder(
+        "test".into(),
+        MockMakeWriter::new(buffer.clone()),
+    ))
+    .build()
+    .unwrap();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, action);
+
+    let buffer_guard = buffer.lock().unwrap();
+    String::from_utf8(buffer_guard.to_vec()).unwrap()
+}
+
+// Run a closure and collect the output emitted by a `BunyanFormattingLayer` configured
+// by `configure`, as structured new-line-delimited JSON.
+fn run_and_get_output_with<C, F>(configure: C, action: F) -> Vec<Value>
+where
+    C: FnOnce(
+        BunyanFormattingLayerBuilder<MockMakeWriter>,
+    ) -> BunyanFormattingLayerBuilder<MockMakeWriter>,
+    F: Fn(),
+{
+    run_and_get_raw_output_with(configure, action)
+        .lines()
+        .filter(|&l| !l.trim().is_empty())
+        .inspect(|l| println!("{}", l))
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect()
+}
+
+// The keys of a JSON object, in order, including duplicates.
+struct Keys(Vec<String>);
+
+impl<'de> serde::Deserialize<'de> for Keys {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeysVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for KeysVisitor {
+            type Value = Keys;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Keys, A::Error> {
+                let mut keys = Vec::new();
+                while let Some((key, _)) = map.next_entry::<String, serde::de::IgnoredAny>()? {
+                    keys.push(key);
+                }
+                Ok(Keys(keys))
+            }
+        }
+
+        deserializer.deserialize_map(KeysVisitor)
+    }
+}
+
+fn assert_unique_keys(raw_output: &str) {
+    for line in raw_output.lines().filter(|l| !l.trim().is_empty()) {
+        let Keys(keys) = serde_json::from_str(line).unwrap();
+        let unique: std::collections::HashSet<_> = keys.iter().collect();
+        assert_eq!(unique.len(), keys.len(), "Duplicate keys in {}", line);
+    }
+}
+
 // Instrumented code to be run to test the behaviour of the tracing instrumentation.
 fn test_action() {
     let a = 2;
@@ -102,6 +188,75 @@ fn time_is_formatted_according_to_rfc_3339() {
     }
 }
 
+#[test]
+fn time_can_be_frozen_for_deterministic_output() {
+    let at = datetime!(2020-01-02 03:04:05.123456789 UTC);
+    let tracing_output =
+        run_and_get_output_with(|builder| builder.timer(BunyanTime::frozen(at)), test_action);
+
+    assert!(!tracing_output.is_empty());
+    for record in tracing_output {
+        assert_eq!(record["time"], json!("2020-01-02T03:04:05.123456789Z"));
+    }
+}
+
+#[test]
+fn time_precision_and_offset_are_configurable() {
+    let cases = [
+        (TimestampPrecision::Millis, "2020-01-02T03:04:05.120+02:00"),
+        (
+            TimestampPrecision::Micros,
+            "2020-01-02T03:04:05.120000+02:00",
+        ),
+        (
+            TimestampPrecision::Nanos,
+            "2020-01-02T03:04:05.120000000+02:00",
+        ),
+        (TimestampPrecision::Auto, "2020-01-02T03:04:05.12+02:00"),
+    ];
+    for (precision, expected) in cases {
+        let at = datetime!(2020-01-02 03:04:05.12 +2);
+        let tracing_output = run_and_get_output_with(
+            |builder| builder.timer(BunyanTime::frozen(at).precision(precision)),
+            test_action,
+        );
+        assert_eq!(tracing_output[0]["time"], json!(expected));
+    }
+}
+
+#[test]
+fn offsets_with_seconds_are_rounded_to_the_closest_minute() {
+    let cases = [
+        (
+            datetime!(2020-01-02 03:04:05 +05:30:15),
+            "2020-01-02T03:03:50+05:30",
+        ),
+        (
+            datetime!(2020-01-02 03:04:05 -05:30:45),
+            "2020-01-02T03:03:50-05:31",
+        ),
+    ];
+    for (at, expected) in cases {
+        let tracing_output =
+            run_and_get_output_with(|builder| builder.timer(BunyanTime::frozen(at)), test_action);
+        assert_eq!(tracing_output[0]["time"], json!(expected));
+    }
+}
+
+#[test]
+fn system_clock_uses_the_configured_offset() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder.timer(BunyanTime::with_offset(offset!(-5:30))),
+        test_action,
+    );
+
+    for record in tracing_output {
+        let time = record.get("time").unwrap().as_str().unwrap();
+        let parsed = time::OffsetDateTime::parse(time, &Rfc3339).unwrap();
+        assert_eq!(parsed.offset(), offset!(-5:30));
+    }
+}
+
 #[test]
 fn encode_f64_as_numbers() {
     let f64_value: f64 = 0.5;
@@ -147,6 +302,52 @@ fn parent_properties_are_propagated() {
     }
 }
 
+#[test]
+fn fields_are_inherited_through_deep_span_trees() {
+    let subscriber = Registry::default().with(JsonStorageLayer);
+    tracing::subscriber::with_default(subscriber, || {
+        let root = span!(
+            Level::DEBUG,
+            "root",
+            depth = 0,
+            tenant = "acme",
+            step = None::<&str>
+        );
+        let mut spans = vec![root.clone()];
+        for depth in 1..=1_000 {
+            let parent = spans.last().unwrap();
+            let span = match depth % 3 {
+                0 => span!(parent: parent, Level::DEBUG, "step", depth),
+                _ => span!(parent: parent, Level::DEBUG, "step"),
+            };
+            spans.push(span);
+        }
+        let leaf = spans.last().unwrap();
+        assert_eq!(
+            Value::Object(leaf.json_fields().unwrap()),
+            json!({"depth": 999, "tenant": "acme"})
+        );
+
+        // Changes made to a parent after a child was created don't reach the child.
+        root.record("step", "checkout");
+        root.insert_json_field("tenant", json!("globex"));
+        assert_eq!(leaf.json_fields().unwrap()["tenant"], "acme");
+        let child = span!(parent: &root, Level::DEBUG, "child");
+        assert_eq!(
+            Value::Object(child.json_fields().unwrap()),
+            json!({"depth": 0, "tenant": "globex", "step": "checkout"})
+        );
+
+        // Removing an inherited field doesn't remove it from the parent.
+        assert_eq!(leaf.remove_json_field("tenant"), Some(json!("acme")));
+        assert!(leaf.json_fields().unwrap().get("tenant").is_none());
+        assert_eq!(spans[999].json_fields().unwrap()["tenant"], "acme");
+
+        // Closing the root first would make the registry close the whole tree recursively.
+        while spans.pop().is_some() {}
+    });
+}
+
 #[test]
 fn elapsed_milliseconds_are_present_on_exit_span() {
     let tracing_output = run_and_get_output(test_action);
@@ -189,57 +390,1857 @@ fn skipping_core_fields_is_not_allowed() {
     }
 }
 
-#[cfg(feature = "valuable")]
-mod valuable_tests {
-    use super::run_and_get_output;
-    use serde_json::json;
-    use valuable::Valuable;
+#[test]
+fn builder_overrides_core_fields() {
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .hostname("a-host")
+                .pid(42)
+                .bunyan_version(1)
+                .default_field("custom_field", json!(1))
+        },
+        test_action,
+    );
 
-    #[derive(Valuable)]
-    struct ValuableStruct {
-        a: u64,
-        b: String,
-        c: ValuableEnum,
+    assert!(!tracing_output.is_empty());
+    for record in tracing_output {
+        assert_eq!(record["hostname"], json!("a-host"));
+        assert_eq!(record["pid"], json!(42));
+        assert_eq!(record["v"], json!(1));
+        assert_eq!(record["custom_field"], json!(1));
     }
+}
 
-    #[derive(Valuable)]
-    #[allow(dead_code)]
-    enum ValuableEnum {
-        A,
-        B(u64),
-        C(String),
+#[test]
+fn builder_rejects_invalid_configurations() {
+    let result = BunyanFormattingLayer::builder("test".into(), Vec::new)
+        .skip_fields(["skipped", "msg"])
+        .build();
+    assert_eq!(
+        result.err(),
+        Some(BuildError::SkippedCoreField("msg".to_string()))
+    );
+
+    let result = BunyanFormattingLayer::builder("test".into(), Vec::new)
+        .default_field("time", json!("yesterday"))
+        .build();
+    let err = result.err().unwrap();
+    assert_eq!(err, BuildError::ReservedDefaultField("time".to_string()));
+    assert_eq!(
+        "time is a core field in the bunyan log format, it can't be used as a default field",
+        err.to_string()
+    );
+}
+
+fn messages(records: &[Value]) -> Vec<&str> {
+    records
+        .iter()
+        .map(|record| record["msg"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn span_records_are_emitted_on_creation_and_close_by_default() {
+    let tracing_output = run_and_get_output(test_action);
+
+    assert_eq!(
+        messages(&tracing_output),
+        [
+            "[SHAVING_YAKS - START]",
+            "[SHAVING_YAKS - EVENT] pre-shaving yaks",
+            "[INNER SHAVING - START]",
+            "[INNER SHAVING - EVENT] shaving yaks",
+            "[INNER SHAVING - END]",
+            "[SHAVING_YAKS - END]",
+        ]
+    );
+}
+
+#[test]
+fn span_records_can_be_disabled() {
+    let tracing_output =
+        run_and_get_output_with(|builder| builder.span_events(FmtSpan::NONE), test_action);
+
+    assert_eq!(
+        messages(&tracing_output),
+        [
+            "[SHAVING_YAKS - EVENT] pre-shaving yaks",
+            "[INNER SHAVING - EVENT] shaving yaks",
+        ]
+    );
+}
+
+#[test]
+fn span_records_can_be_emitted_on_enter_and_exit() {
+    let action = || {
+        let span = span!(Level::DEBUG, "polled", a = 1);
+        for i in 0..2 {
+            let _enter = span.enter();
+            info!(i, "polling");
+        }
+    };
+    let tracing_output =
+        run_and_get_output_with(|builder| builder.span_events(FmtSpan::ACTIVE), action);
+
+    assert_eq!(
+        messages(&tracing_output),
+        [
+            "[POLLED - ENTER]",
+            "[POLLED - EVENT] polling",
+            "[POLLED - EXIT]",
+            "[POLLED - ENTER]",
+            "[POLLED - EVENT] polling",
+            "[POLLED - EXIT]",
+        ]
+    );
+    for record in tracing_output {
+        assert_eq!(record["a"], json!(1));
     }
+}
 
-    #[test]
-    fn encode_valuable_composite_types_as_json() {
-        let out = run_and_get_output(|| {
-            let s = ValuableStruct {
-                a: 17,
-                b: "Hello, world!".to_string(),
-                c: ValuableEnum::B(27),
-            };
+#[test]
+fn events_inherit_from_their_contextual_parent() {
+    let action = || {
+        let span = span!(Level::DEBUG, "contextual", parent_property = 1);
+        let _enter = span.enter();
+        info!("in context");
+    };
+    let tracing_output = run_and_get_output(action);
 
-            tracing::info!(s = s.as_value(), "Test info event");
-        });
+    let event = &tracing_output[1];
+    assert_eq!(event["msg"], json!("[CONTEXTUAL - EVENT] in context"));
+    assert_eq!(event["parent_property"], json!(1));
+}
 
-        assert_eq!(out.len(), 1);
-        let entry = &out[0];
+#[test]
+fn events_inherit_from_their_explicit_parent() {
+    let action = || {
+        let explicit = span!(Level::DEBUG, "explicit", explicit_property = 1);
+        let current = span!(Level::DEBUG, "current", current_property = 2);
+        let _enter = current.enter();
+        info!(parent: &explicit, "with explicit parent");
+    };
+    let tracing_output = run_and_get_output(action);
 
-        let s_json = entry
-            .as_object()
-            .expect("expect entry is object")
-            .get("s")
-            .expect("expect entry.s is present");
+    let event = &tracing_output[2];
+    assert_eq!(
+        event["msg"],
+        json!("[EXPLICIT - EVENT] with explicit parent")
+    );
+    assert_eq!(event["explicit_property"], json!(1));
+    assert!(event.get("current_property").is_none());
+}
 
-        assert_eq!(
-            json!({
-                "a": 17,
-                "b": "Hello, world!",
-                "c": {
-                    "B": 27,
-                },
-            }),
-            *s_json
+#[test]
+fn explicit_root_events_have_no_parent() {
+    let action = || {
+        let current = span!(Level::DEBUG, "current", current_property = 2);
+        let _enter = current.enter();
+        info!(parent: None, "explicit root");
+    };
+    let tracing_output = run_and_get_output(action);
+
+    let event = &tracing_output[1];
+    assert_eq!(event["msg"], json!("explicit root"));
+    assert!(event.get("current_property").is_none());
+}
+
+fn colliding_action() {
+    let span = span!(Level::DEBUG, "colliding", user_id = "span", file = "span");
+    let _enter = span.enter();
+    info!(user_id = "event", custom_field = "event", "colliding");
+}
+
+fn colliding_event(configure: impl FnOnce(KeyCollisionPolicy) -> KeyCollisionPolicy) -> Value {
+    let raw_output = run_and_get_raw_output_with(
+        |builder| {
+            builder
+                .default_field("user_id", json!("default"))
+                .default_field("custom_field", json!("default"))
+                .key_collision_policy(configure(KeyCollisionPolicy::default()))
+        },
+        colliding_action,
+    );
+    assert_unique_keys(&raw_output);
+    serde_json::from_str(raw_output.lines().nth(1).unwrap()).unwrap()
+}
+
+#[test]
+fn records_never_have_duplicate_keys() {
+    assert_unique_keys(&run_and_get_raw_output(colliding_action));
+
+    // The last value of a repeated event field wins.
+    let raw_output = run_and_get_raw_output(|| info!(a = 1, a = 2, "repeated"));
+    assert_unique_keys(&raw_output);
+    let event: Value = serde_json::from_str(raw_output.lines().next().unwrap()).unwrap();
+    assert_eq!(event["a"], json!(2));
+}
+
+#[test]
+fn span_fields_win_collisions_by_default() {
+    let event = colliding_event(|policy| policy);
+
+    assert_eq!(event["user_id"], json!("span"));
+    assert_eq!(event["custom_field"], json!("event"));
+    // Keys generated by the layer always win.
+    assert_eq!(event["file"], json!(file!()));
+}
+
+#[test]
+fn collision_policy_is_configurable() {
+    let event = colliding_event(|_| KeyCollisionPolicy::EventWins);
+    assert_eq!(event["user_id"], json!("event"));
+    assert_eq!(event["custom_field"], json!("event"));
+
+    let event = colliding_event(|_| KeyCollisionPolicy::DefaultWins);
+    assert_eq!(event["user_id"], json!("default"));
+    assert_eq!(event["custom_field"], json!("default"));
+}
+
+#[test]
+fn colliding_keys_can_be_namespaced() {
+    let event = colliding_event(|_| KeyCollisionPolicy::Namespace);
+
+    assert_eq!(event["user_id"], json!("event"));
+    assert_eq!(event["span.user_id"], json!("span"));
+    assert_eq!(event["default.user_id"], json!("default"));
+    assert_eq!(event["custom_field"], json!("event"));
+    assert_eq!(event["default.custom_field"], json!("default"));
+    assert_eq!(event["file"], json!(file!()));
+    assert_eq!(event["span.file"], json!("span"));
+}
+
+#[test]
+fn unrecorded_event_fields_do_not_collide() {
+    let action = || {
+        let span = span!(Level::DEBUG, "unrecorded", user_id = 7);
+        let _enter = span.enter();
+        let user_id: Option<u64> = None;
+        info!(user_id, other = tracing::field::Empty, "unrecorded");
+    };
+    for policy in [KeyCollisionPolicy::EventWins, KeyCollisionPolicy::Namespace] {
+        let raw_output =
+            run_and_get_raw_output_with(|builder| builder.key_collision_policy(policy), action);
+        let event: Value = serde_json::from_str(raw_output.lines().nth(1).unwrap()).unwrap();
+
+        assert_eq!(event["user_id"], json!(7));
+        assert!(event.get("span.user_id").is_none());
+        assert!(event.get("other").is_none());
+    }
+}
+
+#[test]
+fn namespaced_fields_are_skipped_by_their_name() {
+    let raw_output = run_and_get_raw_output_with(
+        |builder| {
+            builder
+                .key_collision_policy(KeyCollisionPolicy::Namespace)
+                .skip_fields(["user_id"])
+        },
+        colliding_action,
+    );
+    let event: Value = serde_json::from_str(raw_output.lines().nth(1).unwrap()).unwrap();
+
+    assert!(event.get("user_id").is_none());
+    assert!(event.get("span.user_id").is_none());
+    assert_eq!(event["span.file"], json!("span"));
+}
+
+fn event_keys_with_ordering(field_ordering: FieldOrdering) -> Vec<String> {
+    let action = || {
+        let span = span!(Level::DEBUG, "outer", c = 1, b = 2);
+        let _enter = span.enter();
+        let child_span = span!(Level::DEBUG, "inner", a = 3, c = 4);
+        let _enter_child = child_span.enter();
+        info!(y = 5, x = 6, "ordered");
+    };
+    let raw_output = run_and_get_raw_output_with(
+        |builder| {
+            builder
+                .default_field("z_default", json!(7))
+                .default_field("a_default", json!(8))
+                .field_ordering(field_ordering)
+        },
+        action,
+    );
+    let Keys(keys) = serde_json::from_str(raw_output.lines().nth(2).unwrap()).unwrap();
+    keys
+}
+
+const CORE_KEYS: [&str; 10] = [
+    "v", "name", "msg", "level", "hostname", "pid", "time", "target", "line", "file",
+];
+
+#[test]
+fn fields_can_be_ordered_by_declaration() {
+    let keys = event_keys_with_ordering(FieldOrdering::Declaration);
+
+    assert_eq!(keys[..10], CORE_KEYS);
+    assert_eq!(
+        keys[10..],
+        ["z_default", "a_default", "y", "x", "c", "b", "a"]
+    );
+}
+
+#[test]
+fn fields_can_be_ordered_alphabetically() {
+    let keys = event_keys_with_ordering(FieldOrdering::Alphabetical);
+
+    assert_eq!(keys[..10], CORE_KEYS);
+    assert_eq!(
+        keys[10..],
+        ["a", "a_default", "b", "c", "x", "y", "z_default"]
+    );
+}
+
+#[test]
+fn undeclared_span_fields_come_last() {
+    let tracing_output = run_and_get_raw_output_with(
+        |builder| builder.field_ordering(FieldOrdering::Declaration),
+        test_action,
+    );
+    let Keys(keys) = serde_json::from_str(tracing_output.lines().last().unwrap()).unwrap();
+
+    assert_eq!(keys.last().unwrap(), "elapsed_milliseconds");
+}
+
+fn redacted_event(configure: impl FnOnce(RedactionRule) -> RedactionRule) -> Value {
+    let action = || {
+        let span = span!(Level::DEBUG, "login", session_token = "span-secret");
+        let _enter = span.enter();
+        info!(password = "hunter2", user = "luca", "logged in");
+    };
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .default_field("db", json!({"user": "app", "Password": "default-secret"}))
+                .redact(configure(RedactionRule::exact("password")))
+                .redact(RedactionRule::glob("*_token"))
+        },
+        action,
+    );
+    tracing_output[1].clone()
+}
+
+#[test]
+fn matching_fields_are_redacted_wherever_they_come_from() {
+    let event = redacted_event(|rule| rule);
+
+    assert_eq!(event["password"], json!("[REDACTED]"));
+    assert_eq!(event["session_token"], json!("[REDACTED]"));
+    // Nested keys are matched as well, ignoring ASCII case.
+    assert_eq!(
+        event["db"],
+        json!({"user": "app", "Password": "[REDACTED]"})
+    );
+    assert_eq!(event["user"], json!("luca"));
+}
+
+#[test]
+fn redaction_is_configurable() {
+    let cases = [
+        (Redaction::Replace(json!(null)), json!(null)),
+        (Redaction::RevealLast(4), json!("***ter2")),
+        (Redaction::RevealLast(7), json!("*******")),
+        (Redaction::Length, json!("[REDACTED: 7 chars]")),
+    ];
+    for (redaction, expected) in cases {
+        let event = redacted_event(|rule| rule.with(redaction));
+        assert_eq!(event["password"], expected);
+    }
+}
+
+#[test]
+fn rules_can_match_dotted_paths() {
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .default_field(
+                    "db",
+                    json!({"users": [{"name": "app", "secret": "a"}], "secret": "b"}),
+                )
+                .default_field("secret", json!("c"))
+                .redact(RedactionRule::glob("db.users.*"))
+        },
+        || info!("redacted"),
+    );
+
+    assert_eq!(
+        tracing_output[0]["db"],
+        json!({"users": [{"name": "[REDACTED]", "secret": "[REDACTED]"}], "secret": "b"})
+    );
+    assert_eq!(tracing_output[0]["secret"], json!("c"));
+}
+
+#[test]
+fn namespaced_colliding_keys_are_redacted() {
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .default_field("password", json!("default-secret"))
+                .key_collision_policy(KeyCollisionPolicy::Namespace)
+                .redact(RedactionRule::exact("password"))
+        },
+        || info!(password = "hunter2", "redacted"),
+    );
+
+    assert_eq!(tracing_output[0]["password"], json!("[REDACTED]"));
+    assert_eq!(tracing_output[0]["default.password"], json!("[REDACTED]"));
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn rules_can_use_regular_expressions() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder.redact(RedactionRule::regex("^(?i)api_?key$")),
+        || info!(api_key = "k1", apikey = "k2", api_keys = "k3", "redacted"),
+    );
+
+    assert_eq!(tracing_output[0]["api_key"], json!("[REDACTED]"));
+    assert_eq!(tracing_output[0]["apikey"], json!("[REDACTED]"));
+    assert_eq!(tracing_output[0]["api_keys"], json!("k3"));
+
+    let result = BunyanFormattingLayer::builder("test".into(), std::io::stdout)
+        .redact(RedactionRule::regex("("))
+        .build();
+    assert!(matches!(
+        result,
+        Err(BuildError::InvalidRedactionPattern(pattern)) if pattern == "("
+    ));
+}
+
+#[derive(Debug)]
+struct ConfigError {
+    source: std::num::ParseIntError,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid port")
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[test]
+fn errors_are_serialized_as_bunyan_err_objects() {
+    let action = || {
+        let error = ConfigError {
+            source: "eighty".parse::<u16>().unwrap_err(),
+        };
+        let span = span!(
+            Level::DEBUG,
+            "config",
+            cause = &error as &dyn std::error::Error
         );
+        let _enter = span.enter();
+        tracing::error!(err = &error as &dyn std::error::Error, "failed");
+    };
+    let tracing_output = run_and_get_output_with(|builder| builder, action);
+    let expected = json!({
+        "message": "invalid port",
+        "stack": "invalid port\nCaused by: invalid digit found in string",
+        "source": ["invalid digit found in string"],
+    });
+
+    // Both on events and on spans.
+    assert_eq!(tracing_output[1]["err"], expected);
+    assert_eq!(tracing_output[1]["cause"], expected);
+    assert_eq!(tracing_output[2]["cause"], expected);
+}
+
+fn nested_spans_action() {
+    let outer = span!(Level::DEBUG, "outer");
+    let _enter = outer.enter();
+    let inner = span!(Level::DEBUG, "inner");
+    let _enter_inner = inner.enter();
+    info!("nested");
+}
+
+#[test]
+fn span_ids_are_not_emitted_by_default() {
+    let tracing_output = run_and_get_output(nested_spans_action);
+
+    for record in tracing_output {
+        assert!(record.get("span_id").is_none());
+        assert!(record.get("parent_span_id").is_none());
+        assert!(record.get("root_span_id").is_none());
+    }
+}
+
+#[test]
+fn span_ids_allow_rebuilding_the_span_tree() {
+    for span_ids in [SpanIds::Registry, SpanIds::Generated] {
+        let tracing_output =
+            run_and_get_output_with(|builder| builder.span_ids(span_ids), nested_spans_action);
+        // Outer start, inner start, event, inner end, outer end.
+        let [outer_start, inner_start, event, inner_end, outer_end] = &tracing_output[..] else {
+            panic!("Unexpected records: {:?}", tracing_output);
+        };
+
+        let outer_id = &outer_start["span_id"];
+        let inner_id = &inner_start["span_id"];
+        assert!(outer_id.is_u64());
+        assert_ne!(outer_id, inner_id);
+        assert!(outer_start.get("parent_span_id").is_none());
+        assert_eq!(&outer_start["root_span_id"], outer_id);
+        for record in [inner_start, event, inner_end] {
+            assert_eq!(&record["span_id"], inner_id);
+            assert_eq!(&record["parent_span_id"], outer_id);
+            assert_eq!(&record["root_span_id"], outer_id);
+        }
+        assert_eq!(&outer_end["span_id"], outer_id);
+    }
+}
+
+// Run a closure with two `BunyanFormattingLayer`s customised by `configure` stacked on the
+// same registry, e.g. to write to two destinations, and collect the output of each of them.
+fn run_with_two_layers<C, F>(configure: C, action: F) -> [Vec<Value>; 2]
+where
+    C: Fn(
+        BunyanFormattingLayerBuilder<MockMakeWriter>,
+    ) -> BunyanFormattingLayerBuilder<MockMakeWriter>,
+    F: Fn(),
+{
+    let buffers = [Arc::new(Mutex::new(vec![])), Arc::new(Mutex::new(vec![]))];
+    let [first, second] = buffers.clone().map(|buffer| {
+        configure(BunyanFormattingLayer::builder(
+            "test".into(),
+            MockMakeWriter::new(buffer),
+        ))
+        .build()
+        .unwrap()
+    });
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(first)
+        .with(second);
+    tracing::subscriber::with_default(subscriber, action);
+
+    buffers.map(|buffer| {
+        let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+        output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    })
+}
+
+#[test]
+fn stacked_layers_share_generated_span_ids() {
+    let [first, second] = run_with_two_layers(
+        |builder| builder.span_ids(SpanIds::Generated),
+        nested_spans_action,
+    );
+
+    assert_eq!(first.len(), 5);
+    assert!(first[2]["span_id"].is_u64());
+    assert!(first[2]["parent_span_id"].is_u64());
+    assert_eq!(without_time(first), without_time(second));
+}
+
+#[test]
+fn generated_span_ids_are_never_reused() {
+    let action = || {
+        for _ in 0..2 {
+            let _span = span!(Level::DEBUG, "short_lived");
+        }
+    };
+    let generated_output =
+        run_and_get_output_with(|builder| builder.span_ids(SpanIds::Generated), action);
+    assert_ne!(
+        generated_output[0]["span_id"],
+        generated_output[2]["span_id"]
+    );
+}
+
+#[test]
+fn events_outside_spans_have_no_span_ids() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder.span_ids(SpanIds::Generated),
+        || info!(span_id = "user", "no span"),
+    );
+
+    // The key is reserved as soon as span ids are enabled.
+    assert!(tracing_output[0].get("span_id").is_none());
+}
+
+fn span_scope_action() {
+    let request = span!(
+        Level::DEBUG,
+        "http_request",
+        method = "GET",
+        user = tracing::field::Empty
+    );
+    let _enter = request.enter();
+    request.record("user", "jane");
+    let query = span!(
+        Level::DEBUG,
+        "db_query",
+        table = "users",
+        password = "secret"
+    );
+    let _enter_query = query.enter();
+    info!("querying");
+}
+
+#[test]
+fn spans_are_not_listed_by_default() {
+    let tracing_output = run_and_get_output(span_scope_action);
+
+    for record in tracing_output {
+        assert!(record.get("spans").is_none());
+        assert!(record.get("span_path").is_none());
+    }
+}
+
+#[test]
+fn span_path_lists_span_names_from_the_root() {
+    let tracing_output =
+        run_and_get_output_with(|builder| builder.span_path(true), span_scope_action);
+
+    assert_eq!(tracing_output[0]["span_path"], "http_request");
+    assert_eq!(tracing_output[2]["span_path"], "http_request/db_query");
+    assert!(tracing_output[2].get("spans").is_none());
+}
+
+#[test]
+fn span_list_keeps_the_own_fields_of_each_span() {
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .span_list(true)
+                .span_ids(SpanIds::Generated)
+                .redact(RedactionRule::exact("password"))
+        },
+        span_scope_action,
+    );
+    let event = &tracing_output[2];
+
+    assert_eq!(
+        event["spans"],
+        json!([
+            {
+                "name": "http_request",
+                "id": tracing_output[0]["span_id"],
+                "fields": {"method": "GET", "user": "jane"},
+            },
+            {
+                "name": "db_query",
+                "id": tracing_output[1]["span_id"],
+                "fields": {"password": "[REDACTED]", "table": "users"},
+            },
+        ])
+    );
+    // The top-level fields still merge the fields of all the spans.
+    assert_eq!(event["method"], "GET");
+    assert_eq!(event["table"], "users");
+}
+
+#[test]
+fn stacked_layers_share_span_lists() {
+    let [first, second] = run_with_two_layers(|builder| builder.span_list(true), span_scope_action);
+
+    assert_eq!(first[2]["spans"][1]["fields"]["table"], "users");
+    assert_eq!(without_time(first), without_time(second));
+}
+
+#[test]
+fn json_fields_can_be_inserted_into_the_current_span() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder.span_list(true),
+        || {
+            let span = span!(Level::DEBUG, "checkout", step = "cart");
+            let _enter = span.enter();
+            assert!(span
+                .insert_json_field("cart", json!({"items": [1, 2], "total": 42.5}))
+                .is_none());
+            assert_eq!(
+                span.insert_json_field("step", json!("payment")),
+                Some(json!("cart"))
+            );
+            info!("paying");
+            let _child = span!(Level::DEBUG, "payment");
+        },
+    );
+    let [_, event, child_start, child_end, span_end] = &tracing_output[..] else {
+        panic!("Unexpected records: {:?}", tracing_output);
+    };
+
+    for record in [event, child_start, child_end, span_end] {
+        assert_eq!(record["cart"], json!({"items": [1, 2], "total": 42.5}));
+        assert_eq!(record["step"], "payment");
+    }
+    assert_eq!(event["spans"][0]["fields"]["cart"]["total"], 42.5);
+}
+
+#[test]
+fn json_fields_can_be_removed_from_the_current_span() {
+    let tracing_output = run_and_get_output(|| {
+        let span = span!(Level::DEBUG, "checkout", step = "cart");
+        let _enter = span.enter();
+        assert_eq!(span.remove_json_field("step"), Some(json!("cart")));
+        assert_eq!(span.remove_json_field("step"), None);
+        info!("paying");
+    });
+
+    assert_eq!(tracing_output[0]["step"], "cart");
+    for record in &tracing_output[1..] {
+        assert!(record.get("step").is_none());
+    }
+}
+
+#[test]
+fn span_fields_can_be_read_from_application_code() {
+    let subscriber = Registry::default().with(JsonStorageLayer);
+    tracing::subscriber::with_default(subscriber, || {
+        let request = span!(Level::DEBUG, "http_request", request_id = "abc");
+        let _enter = request.enter();
+        let query = span!(Level::DEBUG, "db_query", table = "users");
+        let _enter_query = query.enter();
+        tracing::Span::current().insert_json_field("rows", json!([1, 2]));
+
+        let fields = tracing::Span::current().json_fields().unwrap();
+        assert_eq!(
+            Value::Object(fields),
+            json!({"request_id": "abc", "table": "users", "rows": [1, 2]})
+        );
+        assert_eq!(
+            Value::Object(request.json_fields().unwrap()),
+            json!({"request_id": "abc"})
+        );
+    });
+}
+
+#[test]
+fn inserting_json_fields_without_storage_does_nothing() {
+    let subscriber = Registry::default();
+    tracing::subscriber::with_default(subscriber, || {
+        let span = span!(Level::DEBUG, "checkout");
+        assert!(span.insert_json_field("cart", json!(1)).is_none());
+        assert!(span.remove_json_field("cart").is_none());
+        assert!(span.json_fields().is_none());
+    });
+    assert!(tracing::Span::none()
+        .insert_json_field("cart", json!(1))
+        .is_none());
+    assert!(tracing::Span::current().json_fields().is_none());
+}
+
+fn run_with_layers<L>(layers: impl FnOnce(BunyanFormattingLayer<MockMakeWriter>) -> L) -> Vec<Value>
+where
+    L: tracing_subscriber::Layer<Registry> + Send + Sync,
+{
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let formatting_layer =
+        BunyanFormattingLayer::new("test".into(), MockMakeWriter::new(buffer.clone()));
+    let subscriber = Registry::default().with(layers(formatting_layer));
+    tracing::subscriber::with_default(subscriber, || {
+        let outer = span!(Level::DEBUG, "outer", a = 1, b = tracing::field::Empty);
+        let _enter = outer.enter();
+        outer.record("b", 2);
+        let inner = span!(Level::TRACE, "inner", c = 3);
+        let _enter_inner = inner.enter();
+        inner.record("c", 4);
+        info!("nested");
+    });
+
+    let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+    output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn records_have_no_span_fields_without_storage() {
+    let tracing_output = run_with_layers(|formatting_layer| formatting_layer);
+
+    assert_eq!(tracing_output.len(), 5);
+    assert_eq!(tracing_output[2]["msg"], "[INNER - EVENT] nested");
+    assert!(tracing_output[2].get("a").is_none());
+}
+
+#[test]
+fn storage_can_be_filtered_per_layer() {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::Layer;
+
+    let tracing_output = run_with_layers(|formatting_layer| {
+        JsonStorageLayer
+            .with_filter(LevelFilter::DEBUG)
+            .and_then(formatting_layer)
+    });
+
+    // The fields of the spans filtered out of the storage are missing, but not the ones of
+    // their ancestors.
+    assert_eq!(tracing_output.len(), 5);
+    let event = &tracing_output[2];
+    assert_eq!(event["a"], 1);
+    assert_eq!(event["b"], 2);
+    assert!(event.get("c").is_none());
+}
+
+#[test]
+fn formatting_can_be_filtered_per_layer() {
+    use tracing_subscriber::filter::filter_fn;
+    use tracing_subscriber::Layer;
+
+    let tracing_output = run_with_layers(|formatting_layer| {
+        JsonStorageLayer.and_then(
+            formatting_layer.with_filter(filter_fn(|metadata| metadata.name() != "outer")),
+        )
+    });
+
+    // The records of the spans filtered out are missing, but not their fields.
+    assert_eq!(
+        messages(&tracing_output),
+        ["[INNER - START]", "[INNER - EVENT] nested", "[INNER - END]"]
+    );
+    assert_eq!(tracing_output[1]["a"], 1);
+    assert_eq!(tracing_output[1]["c"], 4);
+}
+
+#[test]
+fn storage_is_created_for_spans_that_predate_it() {
+    use tracing_subscriber::reload;
+
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let formatting_layer =
+        BunyanFormattingLayer::new("test".into(), MockMakeWriter::new(buffer.clone()));
+    let (storage_layer, handle) = reload::Layer::new(None::<JsonStorageLayer>);
+    let subscriber = Registry::default()
+        .with(storage_layer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = span!(Level::DEBUG, "early", a = tracing::field::Empty);
+        let _enter = span.enter();
+        handle.reload(Some(JsonStorageLayer)).unwrap();
+        span.record("a", 1);
+        info!("late");
+    });
+
+    let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+    let tracing_output: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(tracing_output[1]["a"], 1);
+    assert_eq!(tracing_output[2]["a"], 1);
+    assert!(tracing_output[2]["elapsed_milliseconds"].is_u64());
+}
+
+fn run_with_json_storage<L>(downstream: L, action: impl Fn()) -> Vec<Value>
+where
+    L: tracing_subscriber::Layer<
+            tracing_subscriber::layer::Layered<BunyanFormattingLayer<MockMakeWriter>, Registry>,
+        > + Send
+        + Sync,
+{
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let formatting_layer =
+        BunyanFormattingLayer::builder("test".into(), MockMakeWriter::new(buffer.clone()))
+            .json_storage(true)
+            .build()
+            .unwrap();
+    let subscriber = Registry::default().with(formatting_layer).with(downstream);
+    tracing::subscriber::with_default(subscriber, action);
+
+    let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+    output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn without_time(mut records: Vec<Value>) -> Vec<Value> {
+    for record in &mut records {
+        record.as_object_mut().unwrap().remove("time");
+    }
+    records
+}
+
+#[test]
+fn span_fields_can_be_stored_by_the_formatting_layer() {
+    let action = || {
+        span_scope_action();
+        let span = span!(Level::DEBUG, "checkout");
+        let _enter = span.enter();
+        span.insert_json_field("cart", json!({"items": 3}));
+        info!("paying");
+    };
+    let combined = run_with_json_storage(tracing_subscriber::layer::Identity::new(), action);
+    let layered = run_and_get_output_with(|builder| builder, action);
+
+    assert_eq!(without_time(combined.clone()), without_time(layered));
+    assert_eq!(combined[7]["cart"], json!({"items": 3}));
+    assert!(combined.last().unwrap()["elapsed_milliseconds"].is_u64());
+}
+
+#[test]
+fn json_storage_layer_can_be_registered_alongside_the_formatting_layer_storage() {
+    let combined = run_with_json_storage(JsonStorageLayer, span_scope_action);
+    let layered = run_and_get_output_with(|builder| builder, span_scope_action);
+
+    assert_eq!(without_time(combined), without_time(layered));
+}
+
+#[test]
+fn json_storage_is_covariant() {
+    fn shorten<'a>(storage: JsonStorage<'static>) -> JsonStorage<'a> {
+        storage
+    }
+    assert!(shorten(JsonStorage::default()).values().is_empty());
+}
+
+/// A downstream layer collecting the keys stored for the span of each event.
+struct StorageReader(Arc<Mutex<Vec<Vec<String>>>>);
+
+impl<S> tracing_subscriber::Layer<S> for StorageReader
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let span = ctx.event_span(event).unwrap();
+        let extensions = span.extensions();
+        let storage = extensions
+            .get::<tracing_bunyan_formatter::JsonStorage>()
+            .unwrap();
+        let mut keys: Vec<String> = storage.values().keys().map(|key| key.to_string()).collect();
+        keys.sort();
+        self.0.lock().unwrap().push(keys);
+    }
+}
+
+#[test]
+fn span_storage_is_published_to_downstream_layers() {
+    let keys = Arc::new(Mutex::new(vec![]));
+    run_with_json_storage(StorageReader(keys.clone()), span_scope_action);
+
+    assert_eq!(
+        *keys.lock().unwrap(),
+        [["method", "password", "table", "user"]]
+    );
+}
+
+// A writer stuck on its first record until it is released, to fill the queue of a
+// non-blocking writer.
+#[derive(Clone)]
+struct StuckWriter {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    stuck: std::sync::mpsc::SyncSender<()>,
+    released: Arc<(Mutex<bool>, std::sync::Condvar)>,
+}
+
+impl std::io::Write for StuckWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _ = self.stuck.try_send(());
+        let (released, condvar) = &*self.released;
+        let _guard = condvar
+            .wait_while(released.lock().unwrap(), |released| !*released)
+            .unwrap();
+        self.buffer.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn non_blocking_messages(overflow_policy: OverflowPolicy, emit: fn(u32)) -> (Vec<Value>, u64) {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let (stuck, is_stuck) = std::sync::mpsc::sync_channel(1);
+    let released = Arc::new((Mutex::new(false), std::sync::Condvar::new()));
+    let writer = StuckWriter {
+        buffer: buffer.clone(),
+        stuck,
+        released: released.clone(),
+    };
+    let (non_blocking, guard) = NonBlockingBuilder::default()
+        .buffered_records(2)
+        .overflow_policy(overflow_policy)
+        .finish(writer);
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), non_blocking.clone());
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+
+    tracing::subscriber::with_default(subscriber, || {
+        info!("1");
+        is_stuck.recv().unwrap();
+        // Release the writer later on, in case emitting records blocks.
+        let releaser = {
+            let released = released.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                *released.0.lock().unwrap() = true;
+                released.1.notify_all();
+            })
+        };
+        for i in 2..=5 {
+            emit(i);
+        }
+        releaser.join().unwrap();
+    });
+    drop(guard);
+
+    let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+    assert_unique_keys(&output);
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect();
+    (records, non_blocking.dropped_records())
+}
+
+#[test]
+fn non_blocking_writer_can_block_when_full() {
+    let (records, dropped) = non_blocking_messages(OverflowPolicy::Block, |i| info!("{}", i));
+
+    assert_eq!(messages(&records), ["1", "2", "3", "4", "5"]);
+    assert!(records.iter().all(|r| r.get("dropped_records").is_none()));
+    assert_eq!(dropped, 0);
+}
+
+#[test]
+fn non_blocking_writer_can_drop_newest_records() {
+    let (records, dropped) = non_blocking_messages(OverflowPolicy::DropNewest, |i| info!("{}", i));
+
+    assert_eq!(messages(&records), ["1", "2", "3"]);
+    assert_eq!(records[1]["dropped_records"], json!(2));
+    assert!(records[2].get("dropped_records").is_none());
+    assert_eq!(dropped, 2);
+}
+
+#[test]
+fn dropped_records_do_not_collide_with_fields() {
+    let (records, _) = non_blocking_messages(OverflowPolicy::DropNewest, |i| {
+        info!(dropped_records = "not a number", "{}", i)
+    });
+
+    assert_eq!(records[1]["dropped_records"], json!("not a number"));
+    assert_eq!(records[1]["non_blocking.dropped_records"], json!(2));
+
+    // Without a non-blocking writer, the key is not used by the layer.
+    let tracing_output = run_and_get_output(|| info!(dropped_records = 3, "plain"));
+    assert_eq!(tracing_output[0]["dropped_records"], json!(3));
+}
+
+#[test]
+fn non_blocking_writer_can_drop_oldest_records() {
+    let (records, dropped) = non_blocking_messages(OverflowPolicy::DropOldest, |i| info!("{}", i));
+
+    assert_eq!(messages(&records), ["1", "4", "5"]);
+    assert_eq!(records[1]["dropped_records"], json!(2));
+    assert_eq!(dropped, 2);
+}
+
+#[test]
+fn non_blocking_write_failures_are_reported() {
+    let reported = Arc::new(Mutex::new(vec![]));
+    let error_handler = {
+        let reported = reported.clone();
+        ErrorHandler::callback(move |error| {
+            let record = error
+                .record()
+                .map(|r| String::from_utf8(r.to_vec()).unwrap());
+            reported
+                .lock()
+                .unwrap()
+                .push((error.kind(), error.metadata().is_some(), record));
+        })
+    };
+    let (non_blocking, guard) = NonBlockingBuilder::default()
+        .error_handler(error_handler)
+        .finish(FailingWriter);
+    let counters = non_blocking.error_counters();
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), non_blocking);
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+
+    tracing::subscriber::with_default(subscriber, || {
+        info!("unwritable");
+    });
+    drop(guard);
+
+    assert_eq!(counters.io_errors(), 1);
+    let reported = reported.lock().unwrap();
+    assert_eq!(reported.len(), 1);
+    assert_eq!(reported[0].0, FormattingErrorKind::Io);
+    assert!(!reported[0].1);
+    let record: Value = serde_json::from_str(reported[0].2.as_ref().unwrap()).unwrap();
+    assert_eq!(record["msg"], json!("unwritable"));
+}
+
+#[test]
+fn default_message_fields_are_not_emitted_on_events() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder.default_field("message", json!("default")),
+        test_action,
+    );
+
+    assert_eq!(tracing_output[0]["message"], json!("default"));
+    assert_eq!(
+        tracing_output[1]["msg"],
+        json!("[SHAVING_YAKS - EVENT] pre-shaving yaks")
+    );
+    assert!(tracing_output[1].get("message").is_none());
+}
+
+#[test]
+fn source_location_is_flat_by_default() {
+    let tracing_output = run_and_get_output(test_action);
+
+    for record in tracing_output {
+        assert!(record.get("src").is_none());
+        assert!(record.get("target").is_some());
+        assert!(record.get("line").is_some());
+        assert!(record.get("file").is_some());
+    }
+}
+
+#[test]
+fn source_location_can_be_nested_under_src() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder.source_location(SourceLocation::Nested),
+        test_action,
+    );
+
+    assert!(!tracing_output.is_empty());
+    for record in tracing_output {
+        assert!(record.get("line").is_none());
+        assert!(record.get("file").is_none());
+        assert_eq!(record["target"], json!("e2e"));
+        assert_eq!(record["src"]["file"], json!(file!()));
+        assert!(record["src"]["line"].is_u64());
+        assert_eq!(record["src"]["func"], json!("e2e"));
+    }
+}
+
+#[test]
+fn src_and_its_sub_keys_can_be_skipped() {
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .source_location(SourceLocation::Nested)
+                .skip_fields(["src.func"])
+        },
+        test_action,
+    );
+    for record in tracing_output {
+        assert!(record["src"].get("file").is_some());
+        assert!(record["src"].get("func").is_none());
+    }
+
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder
+                .source_location(SourceLocation::Nested)
+                .skip_fields(["src"])
+        },
+        test_action,
+    );
+    for record in tracing_output {
+        assert!(record.get("src").is_none());
+    }
+}
+
+#[cfg(feature = "valuable")]
+mod valuable_tests {
+    use super::run_and_get_output;
+    use serde_json::json;
+    use valuable::Valuable;
+
+    #[derive(Valuable)]
+    struct ValuableStruct {
+        a: u64,
+        b: String,
+        c: ValuableEnum,
+    }
+
+    #[derive(Valuable)]
+    #[allow(dead_code)]
+    enum ValuableEnum {
+        A,
+        B(u64),
+        C(String),
+    }
+
+    #[test]
+    fn encode_valuable_composite_types_as_json() {
+        let out = run_and_get_output(|| {
+            let s = ValuableStruct {
+                a: 17,
+                b: "Hello, world!".to_string(),
+                c: ValuableEnum::B(27),
+            };
+
+            tracing::info!(s = s.as_value(), "Test info event");
+        });
+
+        assert_eq!(out.len(), 1);
+        let entry = &out[0];
+
+        let s_json = entry
+            .as_object()
+            .expect("expect entry is object")
+            .get("s")
+            .expect("expect entry.s is present");
+
+        assert_eq!(
+            json!({
+                "a": 17,
+                "b": "Hello, world!",
+                "c": {
+                    "B": 27,
+                },
+            }),
+            *s_json
+        );
+    }
+}
+
+struct FailingWriter;
+
+impl std::io::Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "disk full"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct FailingDebug;
+
+impl std::fmt::Debug for FailingDebug {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Err(std::fmt::Error)
+    }
+}
+
+fn run_with_error_handler<W>(make_writer: W, error_handler: ErrorHandler) -> ErrorCounters
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), make_writer)
+        .error_handler(error_handler)
+        .build()
+        .unwrap();
+    let counters = formatting_layer.error_counters();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        info!(broken = ?FailingDebug, "unserializable");
+        info!("unwritable");
+    });
+    counters
+}
+
+#[test]
+fn failures_are_counted() {
+    let counters = run_with_error_handler(|| FailingWriter, ErrorHandler::ignore());
+
+    assert_eq!(counters.serialization_errors(), 1);
+    assert_eq!(counters.io_errors(), 1);
+}
+
+#[test]
+fn failing_debug_fields_are_reported_when_redaction_rules_are_set() {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let formatting_layer =
+        BunyanFormattingLayer::builder("test".into(), MockMakeWriter::new(buffer.clone()))
+            .redact(RedactionRule::exact("password"))
+            .build()
+            .unwrap();
+    let counters = formatting_layer.error_counters();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+
+    tracing::subscriber::with_default(subscriber, || {
+        info!(broken = ?FailingDebug, "unserializable");
+    });
+
+    assert_eq!(counters.serialization_errors(), 1);
+    assert!(buffer.lock().unwrap().is_empty());
+}
+
+#[test]
+fn failures_are_reported_to_the_error_handler() {
+    let reported = Arc::new(Mutex::new(vec![]));
+    let error_handler = {
+        let reported = reported.clone();
+        ErrorHandler::callback(move |error| {
+            let record = error
+                .record()
+                .map(|r| String::from_utf8(r.to_vec()).unwrap());
+            reported.lock().unwrap().push((error.kind(), record));
+        })
+    };
+    run_with_error_handler(|| FailingWriter, error_handler);
+
+    let reported = reported.lock().unwrap();
+    assert_eq!(reported.len(), 2);
+    assert_eq!(reported[0], (FormattingErrorKind::Serialization, None));
+    assert_eq!(reported[1].0, FormattingErrorKind::Io);
+    let record: Value = serde_json::from_str(reported[1].1.as_ref().unwrap()).unwrap();
+    assert_eq!(record["msg"], json!("unwritable"));
+}
+
+#[test]
+fn unwritable_records_can_be_written_to_a_fallback_writer() {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    run_with_error_handler(
+        || FailingWriter,
+        ErrorHandler::fallback(MockMakeWriter::new(buffer.clone())),
+    );
+
+    let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("Failed to serialize a record for event"));
+    let record: Value = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(record["msg"], json!("unwritable"));
+}
+
+#[test]
+#[should_panic(expected = "Failed to serialize a record")]
+fn error_handler_can_panic() {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    run_with_error_handler(MockMakeWriter::new(buffer), ErrorHandler::panic());
+}
+
+fn stream_messages(buffer: &Arc<Mutex<Vec<u8>>>) -> Vec<String> {
+    String::from_utf8(buffer.lock().unwrap().to_vec())
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap();
+            record["msg"].as_str().unwrap().to_owned()
+        })
+        .collect()
+}
+
+#[test]
+fn records_are_routed_to_streams_by_level_and_target() {
+    let all = Arc::new(Mutex::new(vec![]));
+    let warnings = Arc::new(Mutex::new(vec![]));
+    let http = Arc::new(Mutex::new(vec![]));
+    let streams = Streams::default()
+        .stream(Stream::new(MockMakeWriter::new(all.clone())))
+        .stream(Stream::new(MockMakeWriter::new(warnings.clone())).level(Level::WARN))
+        .stream(
+            Stream::new(MockMakeWriter::new(http.clone()))
+                .target("app::http")
+                .target("hyper"),
+        );
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), streams);
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        info!(target: "app", "started");
+        tracing::warn!(target: "app::http", "slow request");
+        info!(target: "app::https", "handshake");
+        tracing::error!(target: "hyper", "connection reset");
+    });
+
+    assert_eq!(
+        stream_messages(&all),
+        ["started", "slow request", "handshake", "connection reset"]
+    );
+    assert_eq!(
+        stream_messages(&warnings),
+        ["slow request", "connection reset"]
+    );
+    assert_eq!(stream_messages(&http), ["slow request", "connection reset"]);
+}
+
+#[test]
+fn records_are_routed_to_streams_by_bunyan_level() {
+    let fatal = Arc::new(Mutex::new(vec![]));
+    let warnings = Arc::new(Mutex::new(vec![]));
+    let streams = Streams::default()
+        .stream(Stream::new(MockMakeWriter::new(fatal.clone())).level(BunyanLevel::FATAL))
+        .stream(Stream::new(MockMakeWriter::new(warnings.clone())).level(Level::WARN));
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), streams);
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        info!(bunyan.level = "fatal", "out of memory");
+        tracing::error!("connection reset");
+        tracing::warn!(bunyan.level = "info", "slow request");
+    });
+
+    assert_eq!(stream_messages(&fatal), ["out of memory"]);
+    assert_eq!(
+        stream_messages(&warnings),
+        ["out of memory", "connection reset"]
+    );
+}
+
+#[test]
+fn records_are_written_to_the_other_streams_when_one_fails() {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let streams = Streams::default()
+        .stream(Stream::new(|| FailingWriter))
+        .stream(Stream::new(MockMakeWriter::new(buffer.clone())));
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), streams);
+    let counters = formatting_layer.error_counters();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || info!("hello"));
+
+    assert_eq!(stream_messages(&buffer), ["hello"]);
+    assert_eq!(counters.io_errors(), 1);
+}
+
+#[test]
+fn events_can_override_their_level() {
+    let tracing_output = run_and_get_output_with(
+        |builder| builder,
+        || {
+            tracing::error!(bunyan.level = "fatal", "out of memory");
+            info!(bunyan.level = 35, "custom");
+            info!(bunyan.level = "critical", "unknown");
+        },
+    );
+
+    let levels: Vec<_> = tracing_output.iter().map(|r| r["level"].clone()).collect();
+    assert_eq!(levels, [json!(60), json!(35), json!(30)]);
+    assert!(tracing_output
+        .iter()
+        .all(|record| record.get("bunyan.level").is_none()));
+}
+
+#[test]
+fn levels_can_be_mapped() {
+    let tracing_output = run_and_get_output_with(
+        |builder| {
+            builder.level_mapping(|metadata| match metadata.target() {
+                "fatal" => BunyanLevel::FATAL,
+                _ => BunyanLevel::custom(BunyanLevel::from(metadata.level()).as_u16() + 1),
+            })
+        },
+        || {
+            let span = span!(Level::DEBUG, "mapped");
+            let _enter = span.enter();
+            tracing::error!(target: "fatal", "out of memory");
+            tracing::warn!(bunyan.level = "info", "overridden");
+        },
+    );
+
+    let levels: Vec<_> = tracing_output.iter().map(|r| r["level"].clone()).collect();
+    assert_eq!(levels, [json!(21), json!(60), json!(30), json!(21)]);
+}
+
+type CapturedRecord = (Type, Option<&'static str>, Map<String, Value>, Value);
+
+#[derive(Clone, Default)]
+struct CapturingSink {
+    records: Arc<Mutex<Vec<CapturedRecord>>>,
+}
+
+impl RecordSink for CapturingSink {
+    fn write_record(&self, record: &Record<'_>) -> Result<(), SinkError> {
+        let map = record.to_json_map().map_err(SinkError::serialization)?;
+        let mut json = vec![];
+        record
+            .write_json(&mut json)
+            .map_err(SinkError::serialization)?;
+        let json = serde_json::from_slice(&json).unwrap();
+        let span = record.span().map(|span| span.name());
+        self.records
+            .lock()
+            .unwrap()
+            .push((record.ty(), span, map, json));
+        Ok(())
+    }
+}
+
+#[test]
+fn records_can_be_handed_over_to_custom_sinks() {
+    let sink = CapturingSink::default();
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), sink.clone())
+        .default_field("custom_field", json!("custom_value"))
+        .build()
+        .unwrap();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, test_action);
+
+    let records = sink.records.lock().unwrap();
+    let types: Vec<_> = records.iter().map(|(ty, ..)| *ty).collect();
+    assert_eq!(
+        types,
+        [
+            Type::EnterSpan,
+            Type::Event,
+            Type::EnterSpan,
+            Type::Event,
+            Type::ExitSpan,
+            Type::ExitSpan
+        ]
+    );
+    assert!(records
+        .iter()
+        .all(|(_, span, ..)| *span == Some("shaving_yaks") || *span == Some("inner shaving")));
+    for (_, _, map, json) in records.iter() {
+        assert_eq!(&Value::Object(map.clone()), json);
+        assert_eq!(map["custom_field"], json!("custom_value"));
+    }
+}
+
+struct RejectingSink;
+
+impl RecordSink for RejectingSink {
+    fn write_record(&self, _record: &Record<'_>) -> Result<(), SinkError> {
+        Err(SinkError::io(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "database unavailable",
+        )))
+    }
+}
+
+#[test]
+fn sink_errors_are_reported() {
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), RejectingSink);
+    let counters = formatting_layer.error_counters();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || info!("hello"));
+
+    assert_eq!(counters.io_errors(), 1);
+}
+
+fn ring_buffer_messages(records: Vec<Vec<u8>>) -> Vec<String> {
+    records
+        .into_iter()
+        .map(|record| {
+            let record: Value = serde_json::from_slice(&record).unwrap();
+            record["msg"].as_str().unwrap().to_owned()
+        })
+        .collect()
+}
+
+fn run_with_ring_buffer(ring_buffer: &RingBuffer, action: impl Fn()) {
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), ring_buffer.clone());
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, action);
+}
+
+#[test]
+fn ring_buffer_keeps_the_most_recent_records() {
+    let ring_buffer = RingBuffer::new(3);
+    run_with_ring_buffer(&ring_buffer, || {
+        for i in 1..=5 {
+            tracing::debug!("{}", i);
+        }
+    });
+
+    assert_eq!(ring_buffer_messages(ring_buffer.records()), ["3", "4", "5"]);
+
+    let mut dump = vec![];
+    ring_buffer.dump(&mut dump).unwrap();
+    let dumped: Vec<Vec<u8>> = dump
+        .split_inclusive(|byte| *byte == b'\n')
+        .map(<[u8]>::to_vec)
+        .collect();
+    assert_eq!(dumped, ring_buffer.records());
+    assert_eq!(ring_buffer.len(), 3);
+}
+
+#[test]
+fn ring_buffer_is_bounded_in_bytes() {
+    let record_size = {
+        let ring_buffer = RingBuffer::new(10);
+        run_with_ring_buffer(&ring_buffer, || info!("0"));
+        ring_buffer.records()[0].len()
+    };
+    // Room for two records and a half: the size of records varies slightly with their time.
+    let ring_buffer = RingBufferBuilder::default()
+        .max_records(10)
+        .max_bytes(record_size * 5 / 2)
+        .build();
+    run_with_ring_buffer(&ring_buffer, || {
+        for i in 1..=5 {
+            info!("{}", i);
+        }
+        info!("{}", "too large to be kept".repeat(50));
+    });
+
+    assert_eq!(ring_buffer_messages(ring_buffer.records()), ["4", "5"]);
+}
+
+#[test]
+fn ring_buffer_can_be_dumped_on_errors() {
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let ring_buffer = RingBufferBuilder::default()
+        .dump_on(Level::ERROR, MockMakeWriter::new(buffer.clone()))
+        .build();
+    let disk = Arc::new(Mutex::new(vec![]));
+    let streams = Streams::default()
+        .stream(Stream::new(MockMakeWriter::new(disk.clone())).level(Level::INFO))
+        .stream(Stream::new(ring_buffer.clone()));
+    let formatting_layer = BunyanFormattingLayer::new("test".into(), streams);
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::debug!("connecting");
+        info!("request");
+        tracing::error!("failed");
+        tracing::debug!("retrying");
+    });
+
+    assert_eq!(stream_messages(&disk), ["request", "failed"]);
+    assert_eq!(
+        stream_messages(&buffer),
+        ["connecting", "request", "failed"]
+    );
+    assert_eq!(ring_buffer_messages(ring_buffer.records()), ["retrying"]);
+}
+
+// Tests installing a panic hook take turns, so that they don't remove each other's hook.
+static PANIC_HOOK: Mutex<()> = Mutex::new(());
+
+#[test]
+fn ring_buffer_can_be_dumped_on_panic() {
+    let _panic_hook = PANIC_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let ring_buffer = RingBuffer::new(10);
+    ring_buffer.dump_on_panic(MockMakeWriter::new(buffer.clone()));
+    run_with_ring_buffer(&ring_buffer, || info!("before panicking"));
+
+    let result = std::panic::catch_unwind(|| panic!("boom"));
+    let _ = std::panic::take_hook();
+
+    assert!(result.is_err());
+    // Other tests might panic while the hook is installed, dumping the buffer again.
+    let dumped = stream_messages(&buffer);
+    assert!(!dumped.is_empty());
+    assert!(dumped.iter().all(|message| message == "before panicking"));
+}
+
+#[test]
+fn panics_are_emitted_as_fatal_records() {
+    let _panic_hook = PANIC_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    let buffer = Arc::new(Mutex::new(vec![]));
+    let (non_blocking, guard) =
+        NonBlockingBuilder::default().finish(MockWriter::new(buffer.clone()));
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), non_blocking.clone())
+        .default_field("service", json!("api"))
+        .build()
+        .unwrap();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer)
+        .with(formatting_layer);
+    PanicHook::default()
+        .flush(non_blocking)
+        .force_backtrace(true)
+        .call_previous_hook(false)
+        .install();
+
+    let (result, line) = tracing::subscriber::with_default(subscriber, || {
+        let span = span!(Level::INFO, "handler", request_id = 42);
+        let _enter = span.enter();
+        let line = line!() + 1;
+        (std::panic::catch_unwind(|| panic!("boom: {}", 7)), line)
+    });
+    let _ = std::panic::take_hook();
+    assert!(result.is_err());
+
+    // The record has been flushed by the hook, before the writer is shut down.
+    let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+    drop(guard);
+    let record: Value = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .find(|record| record["target"] == "panic")
+        .expect("No record for the panic");
+    assert_eq!(record["level"], 60);
+    assert_eq!(record["msg"], "[HANDLER - EVENT] boom: 7");
+    assert_eq!(record["name"], "test");
+    assert_eq!(record["service"], "api");
+    assert_eq!(record["request_id"], 42);
+    assert_eq!(record["panic.file"], file!());
+    assert_eq!(record["panic.line"], line);
+    assert_eq!(
+        record["thread.name"],
+        std::thread::current().name().unwrap()
+    );
+    assert!(record["backtrace"].as_str().is_some());
+    assert!(record.get("bunyan.level").is_none());
+}
+
+#[cfg(feature = "opentelemetry")]
+mod opentelemetry_ids {
+    use super::*;
+    use opentelemetry::trace::noop::NoopSpan;
+    use opentelemetry::trace::{
+        SamplingDecision, SamplingResult, SpanBuilder, SpanContext, SpanId, TraceContextExt,
+        TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry::Context;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tracing_opentelemetry::{OtelData, PreSampledTracer};
+
+    /// A tracer sampling every span, standing in for the OpenTelemetry SDK.
+    struct SamplingTracer {
+        next_id: AtomicU64,
+    }
+
+    impl SamplingTracer {
+        fn next_id(&self) -> u64 {
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    impl opentelemetry::trace::Tracer for SamplingTracer {
+        type Span = NoopSpan;
+
+        fn build_with_context(&self, _builder: SpanBuilder, _parent_cx: &Context) -> NoopSpan {
+            NoopSpan::DEFAULT
+        }
+    }
+
+    impl PreSampledTracer for SamplingTracer {
+        fn sampled_context(&self, data: &mut OtelData) -> Context {
+            let parent = data.parent_cx.span();
+            let parent = parent.span_context();
+            let trace_id = if parent.is_valid() {
+                parent.trace_id()
+            } else {
+                data.builder.trace_id.unwrap_or(TraceId::INVALID)
+            };
+            data.builder
+                .sampling_result
+                .get_or_insert_with(|| SamplingResult {
+                    decision: SamplingDecision::RecordAndSample,
+                    attributes: Vec::new(),
+                    trace_state: TraceState::default(),
+                });
+            let span_context = SpanContext::new(
+                trace_id,
+                data.builder.span_id.unwrap_or(SpanId::INVALID),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            );
+            data.parent_cx.with_remote_span_context(span_context)
+        }
+
+        fn new_trace_id(&self) -> TraceId {
+            TraceId::from_bytes(u128::from(self.next_id()).to_be_bytes())
+        }
+
+        fn new_span_id(&self) -> SpanId {
+            SpanId::from_bytes(self.next_id().to_be_bytes())
+        }
+    }
+
+    fn run_and_get_output_with_otel<F: Fn()>(
+        configure: impl FnOnce(
+            BunyanFormattingLayerBuilder<MockMakeWriter>,
+        ) -> BunyanFormattingLayerBuilder<MockMakeWriter>,
+        action: F,
+    ) -> Vec<Value> {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        let formatting_layer = configure(BunyanFormattingLayer::builder(
+            "test".into(),
+            MockMakeWriter::new(buffer.clone()),
+        ))
+        .build()
+        .unwrap();
+        let tracer = SamplingTracer {
+            next_id: AtomicU64::new(1),
+        };
+        let subscriber = Registry::default()
+            .with(JsonStorageLayer)
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .with(formatting_layer);
+        tracing::subscriber::with_default(subscriber, action);
+
+        let output = String::from_utf8(buffer.lock().unwrap().to_vec()).unwrap();
+        output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn records_carry_opentelemetry_ids() {
+        let tracing_output = run_and_get_output_with_otel(|builder| builder, nested_spans_action);
+        let [outer_start, inner_start, event, inner_end, outer_end] = &tracing_output[..] else {
+            panic!("Unexpected records: {:?}", tracing_output);
+        };
+
+        let trace_id = outer_start["trace_id"].as_str().unwrap();
+        assert_eq!(trace_id.len(), 32);
+        let outer_span_id = outer_start["span_id"].as_str().unwrap();
+        let inner_span_id = inner_start["span_id"].as_str().unwrap();
+        assert_eq!(outer_span_id.len(), 16);
+        assert_ne!(outer_span_id, inner_span_id);
+        for record in [inner_start, event, inner_end, outer_end] {
+            assert_eq!(record["trace_id"], json!(trace_id));
+        }
+        assert_eq!(event["span_id"], json!(inner_span_id));
+        assert_eq!(outer_end["span_id"], json!(outer_span_id));
+        // The sampling decision of the outer span is made when the inner span is created,
+        // and inherited by the inner span.
+        assert!(outer_start.get("trace_flags").is_none());
+        for record in [inner_start, event, inner_end, outer_end] {
+            assert_eq!(record["trace_flags"], json!("01"));
+        }
+    }
+
+    #[test]
+    fn opentelemetry_ids_can_be_disabled() {
+        let tracing_output = run_and_get_output_with_otel(
+            |builder| builder.opentelemetry_ids(false),
+            nested_spans_action,
+        );
+
+        for record in tracing_output {
+            assert!(record.get("trace_id").is_none());
+            assert!(record.get("span_id").is_none());
+        }
+    }
+
+    #[test]
+    fn opentelemetry_span_ids_are_namespaced_when_span_ids_are_enabled() {
+        let tracing_output = run_and_get_output_with_otel(
+            |builder| builder.span_ids(SpanIds::Registry),
+            nested_spans_action,
+        );
+        let default_output = run_and_get_output_with_otel(|builder| builder, nested_spans_action);
+
+        let event = &tracing_output[2];
+        assert!(event["span_id"].is_u64());
+        assert!(event["trace_id"].is_string());
+        assert_eq!(event["otel.span_id"].as_str().unwrap().len(), 16);
+        assert!(default_output[2].get("otel.span_id").is_none());
+    }
+
+    #[test]
+    fn events_outside_spans_have_no_opentelemetry_ids() {
+        let tracing_output = run_and_get_output_with_otel(|builder| builder, || info!("no span"));
+
+        assert!(tracing_output[0].get("trace_id").is_none());
     }
 }
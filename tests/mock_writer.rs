@@ -1,5 +1,6 @@
 use std::io;
 use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use tracing_subscriber::fmt::MakeWriter;
 
 /// Use a vector of bytes behind a Arc<Mutex> as writer in order to inspect the tracing output
 /// for testing purposes.
@@ -34,3 +35,23 @@ impl io::Write for MockWriter {
         self.buf()?.flush()
     }
 }
+
+/// A `MakeWriter` handing out `MockWriter`s that share the same underlying buffer.
+#[derive(Clone)]
+pub struct MockMakeWriter {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockMakeWriter {
+    pub fn new(buf: Arc<Mutex<Vec<u8>>>) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> MakeWriter<'a> for MockMakeWriter {
+    type Writer = MockWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        MockWriter::new(self.buf.clone())
+    }
+}